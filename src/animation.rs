@@ -0,0 +1,235 @@
+use nalgebra_glm::Vec3;
+use serde::Deserialize;
+use std::f32::consts::PI;
+
+/// Multiplier applied to an intensity over scene time, in seconds. In scene files
+/// it's written as a table naming the variant, e.g. `{ flicker = { speed = 3.0, amount = 0.3 } }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntensityCurve {
+    Constant(f32),
+    /// Irregular torch-like flicker: the intensity dips by up to `amount` (0..1),
+    /// `speed` scaling how fast it changes.
+    Flicker { speed: f32, amount: f32 },
+    /// Smooth cosine pulse between `min` and 1 every `period` seconds.
+    Pulse { period: f32, min: f32 },
+    /// `(time, factor)` pairs sorted by time, linearly interpolated and looped
+    /// over the last key's time.
+    Keyframes(Vec<(f32, f32)>),
+}
+
+impl IntensityCurve {
+    pub fn factor(&self, time: f32) -> f32 {
+        match self {
+            IntensityCurve::Constant(value) => *value,
+            IntensityCurve::Flicker { speed, amount } => {
+                // Incommensurate frequencies so the pattern never visibly repeats.
+                let t = time * speed;
+                let wave = (t * 1.7).sin() * 0.5 + (t * 4.3 + 1.1).sin() * 0.3 + (t * 9.1 + 2.3).sin() * 0.2;
+                1.0 - amount * (0.5 + 0.5 * wave)
+            }
+            IntensityCurve::Pulse { period, min } => {
+                if *period <= 0.0 {
+                    return 1.0;
+                }
+                let phase = (time / period).fract();
+                min + (1.0 - min) * (0.5 - 0.5 * (2.0 * PI * phase).cos())
+            }
+            IntensityCurve::Keyframes(keys) => {
+                let (first, last) = match (keys.first(), keys.last()) {
+                    (Some(first), Some(last)) => (*first, *last),
+                    _ => return 1.0,
+                };
+                if keys.len() == 1 || last.0 <= 0.0 {
+                    return first.1;
+                }
+
+                let t = time.rem_euclid(last.0);
+                if t <= first.0 {
+                    return first.1;
+                }
+                for pair in keys.windows(2) {
+                    let (t0, v0) = pair[0];
+                    let (t1, v1) = pair[1];
+                    if t <= t1 {
+                        let s = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+                        return v0 + (v1 - v0) * s;
+                    }
+                }
+                last.1
+            }
+        }
+    }
+}
+
+/// Remaps progress through a segment, `t` in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Linear,
+    /// Smooth curve through every key, tangents taken from the neighbouring keys.
+    #[default]
+    CatmullRom,
+    /// Cubic Bezier segments using each key's handles, Catmull-Rom tangents where a handle is missing.
+    Bezier,
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraKeyframe {
+    /// Seconds from the start of the path.
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+    /// Vertical field of view in radians.
+    pub fov: Option<f32>,
+    pub focus_distance: Option<f32>,
+    /// Bezier handles for `position`, relative to it.
+    pub in_handle: Option<Vec3>,
+    pub out_handle: Option<Vec3>,
+    /// Easing over the segment that starts at this key.
+    pub easing: Easing,
+}
+
+/// Where the camera is and what it looks at at one moment of a path. Optional
+/// values are only set when some keyframe specifies them.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraPose {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub fov: Option<f32>,
+    pub focus_distance: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraKeyframe>,
+    pub interpolation: Interpolation,
+}
+
+impl CameraPath {
+    pub fn new(mut keyframes: Vec<CameraKeyframe>, interpolation: Interpolation) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        CameraPath {
+            keyframes,
+            interpolation,
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |key| key.time)
+    }
+
+    /// Pose at `time`, holding the first and last keys outside the path.
+    pub fn sample(&self, time: f32) -> Option<CameraPose> {
+        let keys = &self.keyframes;
+        let first = keys.first()?;
+        let last = keys.last()?;
+        if keys.len() == 1 || time <= first.time {
+            return Some(pose_at_key(first));
+        }
+        if time >= last.time {
+            return Some(pose_at_key(last));
+        }
+
+        let i = keys.windows(2).position(|pair| time < pair[1].time)?;
+        let (a, b) = (&keys[i], &keys[i + 1]);
+        let span = (b.time - a.time).max(1e-6);
+        let t = a.easing.apply((time - a.time) / span);
+
+        let position = match self.interpolation {
+            Interpolation::Linear => a.position.lerp(&b.position, t),
+            Interpolation::CatmullRom => {
+                let (out_handle, in_handle) = self.auto_handles(i, |key| key.position);
+                bezier(a.position, a.position + out_handle, b.position + in_handle, b.position, t)
+            }
+            Interpolation::Bezier => {
+                let (out_handle, in_handle) = self.auto_handles(i, |key| key.position);
+                let out_handle = a.out_handle.unwrap_or(out_handle);
+                let in_handle = b.in_handle.unwrap_or(in_handle);
+                bezier(a.position, a.position + out_handle, b.position + in_handle, b.position, t)
+            }
+        };
+        let target = match self.interpolation {
+            Interpolation::Linear => a.target.lerp(&b.target, t),
+            _ => {
+                let (out_handle, in_handle) = self.auto_handles(i, |key| key.target);
+                bezier(a.target, a.target + out_handle, b.target + in_handle, b.target, t)
+            }
+        };
+
+        let blend = |x: Option<f32>, y: Option<f32>| match (x, y) {
+            (Some(x), Some(y)) => Some(x + (y - x) * t),
+            (x, y) => x.or(y),
+        };
+
+        Some(CameraPose {
+            position,
+            target,
+            fov: blend(self.value_before(i, |key| key.fov), self.value_after(i + 1, |key| key.fov)),
+            focus_distance: blend(
+                self.value_before(i, |key| key.focus_distance),
+                self.value_after(i + 1, |key| key.focus_distance),
+            ),
+        })
+    }
+
+    /// Catmull-Rom handles for segment `i`: the out handle of key `i` and the in
+    /// handle of key `i + 1`, each a third of the tangent scaled to the segment length.
+    fn auto_handles(&self, i: usize, value: impl Fn(&CameraKeyframe) -> Vec3) -> (Vec3, Vec3) {
+        let keys = &self.keyframes;
+        let tangent = |k: usize| {
+            let prev = k.saturating_sub(1);
+            let next = (k + 1).min(keys.len() - 1);
+            let dt = (keys[next].time - keys[prev].time).max(1e-6);
+            (value(&keys[next]) - value(&keys[prev])) / dt
+        };
+        let span = keys[i + 1].time - keys[i].time;
+        (tangent(i) * span / 3.0, -tangent(i + 1) * span / 3.0)
+    }
+
+    /// Latest value set at or before key `i`, so unspecified keys hold the previous value.
+    fn value_before(&self, i: usize, value: impl Fn(&CameraKeyframe) -> Option<f32>) -> Option<f32> {
+        self.keyframes[..=i].iter().rev().find_map(value)
+    }
+
+    /// Earliest value set at or after key `i`.
+    fn value_after(&self, i: usize, value: impl Fn(&CameraKeyframe) -> Option<f32>) -> Option<f32> {
+        self.keyframes[i..].iter().find_map(value)
+    }
+}
+
+fn pose_at_key(key: &CameraKeyframe) -> CameraPose {
+    CameraPose {
+        position: key.position,
+        target: key.target,
+        fov: key.fov,
+        focus_distance: key.focus_distance,
+    }
+}
+
+fn bezier(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let u = 1.0 - t;
+    p0 * (u * u * u) + p1 * (3.0 * u * u * t) + p2 * (3.0 * u * t * t) + p3 * (t * t * t)
+}
@@ -0,0 +1,153 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::texture::{FilterMode, Texture, UvDerivatives};
+
+/// Pixel rectangle of a tile inside the atlas image, with `(x, y)` the top-left corner.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    #[serde(alias = "w")]
+    pub width: u32,
+    #[serde(alias = "h")]
+    pub height: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AtlasMapping {
+    tiles: HashMap<String, TileRect>,
+}
+
+/// A tile's region of the atlas in texture coordinates. Lookups are kept half a
+/// texel inside it on whichever mip level they read, and never go below the level
+/// where the tile still covers whole texels, so filtering doesn't blend in neighbours.
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasTile {
+    pub u_min: f32,
+    pub v_min: f32,
+    pub u_max: f32,
+    pub v_max: f32,
+}
+
+impl AtlasTile {
+    fn from_rect(rect: &TileRect, atlas_width: usize, atlas_height: usize) -> Self {
+        let w = atlas_width as f32;
+        let h = atlas_height as f32;
+
+        // Texture v grows upwards while image rows grow downwards.
+        AtlasTile {
+            u_min: rect.x as f32 / w,
+            u_max: (rect.x + rect.width) as f32 / w,
+            v_min: 1.0 - (rect.y + rect.height) as f32 / h,
+            v_max: 1.0 - rect.y as f32 / h,
+        }
+    }
+
+    /// Coarsest mip level of a `width` x `height` atlas whose texels lie entirely
+    /// inside the tile. Level L averages 2^L x 2^L blocks, so the tile keeps texels
+    /// of its own while its corner, its size and the atlas size are multiples of 2^L.
+    pub fn max_level(&self, width: usize, height: usize) -> usize {
+        let texels = |coordinate: f32, size: usize| (coordinate * size as f32).round() as usize;
+        let alignment = texels(self.u_min, width)
+            | texels(self.u_max, width)
+            | texels(self.v_min, height)
+            | texels(self.v_max, height)
+            | width
+            | height;
+        alignment.trailing_zeros() as usize
+    }
+
+    /// Keeps `(u, v)` half a texel inside the tile on a level of `width` x `height` texels.
+    pub fn clamp(&self, u: f32, v: f32, width: usize, height: usize) -> (f32, f32) {
+        let inset_u = (0.5 / width as f32).min((self.u_max - self.u_min) * 0.5);
+        let inset_v = (0.5 / height as f32).min((self.v_max - self.v_min) * 0.5);
+        (
+            u.clamp(self.u_min + inset_u, self.u_max - inset_u),
+            v.clamp(self.v_min + inset_v, self.v_max - inset_v),
+        )
+    }
+
+    /// Maps tile-local coordinates (repeating every unit) into atlas coordinates.
+    pub fn map(&self, u: f32, v: f32, derivatives: &UvDerivatives) -> (f32, f32, UvDerivatives) {
+        let su = self.u_max - self.u_min;
+        let sv = self.v_max - self.v_min;
+        let u = self.u_min + u.rem_euclid(1.0) * su;
+        let v = self.v_min + v.rem_euclid(1.0) * sv;
+
+        let derivatives = UvDerivatives {
+            dudx: derivatives.dudx * su,
+            dvdx: derivatives.dvdx * sv,
+            dudy: derivatives.dudy * su,
+            dvdy: derivatives.dvdy * sv,
+        };
+        (u, v, derivatives)
+    }
+}
+
+/// One image holding many block textures plus a name -> rectangle mapping,
+/// loaded from a JSON or TOML file:
+///
+/// ```toml
+/// [tiles.snow]
+/// x = 0
+/// y = 0
+/// width = 16
+/// height = 16
+/// ```
+#[derive(Debug)]
+pub struct TextureAtlas {
+    pub texture: Arc<Texture>,
+    tiles: HashMap<String, AtlasTile>,
+}
+
+impl TextureAtlas {
+    pub fn new(image_path: &str, mapping_path: &str) -> Result<TextureAtlas, String> {
+        let texture = Texture::load(image_path)?.with_filter(FilterMode::Trilinear);
+        TextureAtlas::from_texture(Arc::new(texture), mapping_path)
+    }
+
+    pub fn from_texture(texture: Arc<Texture>, mapping_path: &str) -> Result<TextureAtlas, String> {
+        let source = fs::read_to_string(mapping_path)
+            .map_err(|e| format!("could not read atlas mapping {}: {}", mapping_path, e))?;
+
+        let is_toml = Path::new(mapping_path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+        let mapping: AtlasMapping = if is_toml {
+            toml::from_str(&source).map_err(|e| format!("{}: {}", mapping_path, e))?
+        } else {
+            serde_json::from_str(&source).map_err(|e| format!("{}: {}", mapping_path, e))?
+        };
+
+        let mut tiles = HashMap::new();
+        for (name, rect) in &mapping.tiles {
+            // Sums that overflow are past the edge of any atlas.
+            let right = rect.x.checked_add(rect.width).map_or(usize::MAX, |right| right as usize);
+            let bottom = rect.y.checked_add(rect.height).map_or(usize::MAX, |bottom| bottom as usize);
+            if rect.width == 0 || rect.height == 0 || right > texture.width || bottom > texture.height {
+                return Err(format!(
+                    "{}: tile '{}' lies outside the {}x{} atlas",
+                    mapping_path, name, texture.width, texture.height
+                ));
+            }
+            tiles.insert(
+                name.clone(),
+                AtlasTile::from_rect(rect, texture.width, texture.height),
+            );
+        }
+
+        Ok(TextureAtlas { texture, tiles })
+    }
+
+    pub fn tile(&self, name: &str) -> Option<AtlasTile> {
+        self.tiles.get(name).copied()
+    }
+
+    pub fn tile_names(&self) -> impl Iterator<Item = &str> {
+        self.tiles.keys().map(|name| name.as_str())
+    }
+}
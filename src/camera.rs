@@ -1,10 +1,241 @@
-use nalgebra_glm::Vec3;
+use nalgebra_glm::{Vec2, Vec3};
+use rand::random;
 use std::f32::consts::PI;
+use std::sync::Arc;
 
+use crate::animation::CameraPose;
+use crate::ray_intersect::{RayCone, RayIntersect};
+use crate::texture::Texture;
+
+const MIN_FOV: f32 = PI / 18.0;
+const MAX_FOV: f32 = PI * 5.0 / 6.0;
+
+/// How wide the camera sees. Angles are full angles in radians; focal length and
+/// sensor height share any unit (millimetres, with a 24 mm full-frame sensor).
+#[derive(Debug, Clone, Copy)]
+pub enum FieldOfView {
+    Vertical(f32),
+    Horizontal(f32),
+    FocalLength { focal_length: f32, sensor_height: f32 },
+}
+
+impl FieldOfView {
+    pub fn vertical(&self, aspect_ratio: f32) -> f32 {
+        match *self {
+            FieldOfView::Vertical(fov) => fov,
+            FieldOfView::Horizontal(fov) => 2.0 * ((fov * 0.5).tan() / aspect_ratio).atan(),
+            FieldOfView::FocalLength {
+                focal_length,
+                sensor_height,
+            } => 2.0 * (sensor_height / (2.0 * focal_length)).atan(),
+        }
+    }
+
+    pub fn horizontal(&self, aspect_ratio: f32) -> f32 {
+        2.0 * ((self.vertical(aspect_ratio) * 0.5).tan() * aspect_ratio).atan()
+    }
+}
+
+/// Shape of the aperture, which is also the shape out-of-focus highlights take.
+#[derive(Debug, Clone)]
+pub enum Bokeh {
+    Circle,
+    /// Regular polygon with one edge per aperture blade.
+    Polygon { blades: u32, rotation: f32 },
+    /// Grayscale mask over the unit disk: brighter texels let more light through.
+    Image(Arc<Texture>),
+}
+
+impl Bokeh {
+    /// Random point on the aperture, inside the unit disk.
+    fn sample(&self) -> Vec2 {
+        match self {
+            Bokeh::Circle => sample_disk(),
+            Bokeh::Polygon { blades, rotation } => {
+                let blades = (*blades).max(3);
+                let step = 2.0 * PI / blades as f32;
+                let blade = (random::<f32>() * blades as f32) as u32 % blades;
+                let angle = rotation + blade as f32 * step;
+                let a = Vec2::new(angle.cos(), angle.sin());
+                let b = Vec2::new((angle + step).cos(), (angle + step).sin());
+
+                // Uniform point in the triangle (center, a, b).
+                let (mut s, mut t) = (random::<f32>(), random::<f32>());
+                if s + t > 1.0 {
+                    s = 1.0 - s;
+                    t = 1.0 - t;
+                }
+                a * s + b * t
+            }
+            Bokeh::Image(mask) => {
+                // Rejection sampling against the mask's brightness.
+                for _ in 0..32 {
+                    let p = Vec2::new(random::<f32>() * 2.0 - 1.0, random::<f32>() * 2.0 - 1.0);
+                    let weight = mask.get_color(p.x * 0.5 + 0.5, p.y * 0.5 + 0.5).luminance();
+                    if random::<f32>() < weight {
+                        return p;
+                    }
+                }
+                Vec2::new(0.0, 0.0)
+            }
+        }
+    }
+}
+
+fn sample_disk() -> Vec2 {
+    let radius = random::<f32>().sqrt();
+    let angle = 2.0 * PI * random::<f32>();
+    Vec2::new(radius * angle.cos(), radius * angle.sin())
+}
+
+/// Thin-lens model: rays leave from a random point on the aperture and converge on
+/// the plane `focus_distance` in front of the eye, blurring everything off that plane.
+/// An `aperture_radius` of zero is a pinhole camera with everything in focus.
+#[derive(Debug, Clone)]
+pub struct Lens {
+    pub aperture_radius: f32,
+    pub focus_distance: f32,
+    pub bokeh: Bokeh,
+    /// Lens samples averaged per pixel while the aperture is open.
+    pub samples: u32,
+}
+
+impl Lens {
+    pub fn pinhole() -> Self {
+        Lens {
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            bokeh: Bokeh::Circle,
+            samples: 1,
+        }
+    }
+
+    /// Aperture from an f-number: the opening is `focal_length / f_stop` wide,
+    /// with `focal_length` in scene units (0.05 for a 50 mm lens when a block is a metre).
+    pub fn from_f_stop(focal_length: f32, f_stop: f32, focus_distance: f32) -> Self {
+        Lens {
+            aperture_radius: focal_length / (2.0 * f_stop.max(1e-3)),
+            focus_distance,
+            ..Lens::pinhole()
+        }
+    }
+
+    pub fn with_bokeh(mut self, bokeh: Bokeh) -> Self {
+        self.bokeh = bokeh;
+        self
+    }
+
+    pub fn with_samples(mut self, samples: u32) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    pub fn is_pinhole(&self) -> bool {
+        self.aperture_radius <= 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    /// Image radius proportional to the angle off-axis.
+    Equidistant,
+    /// Equal solid angles cover equal image areas.
+    Equisolid,
+}
+
+/// How pixels map to ray directions. Perspective and fisheye take their angle from
+/// the camera's field of view; the fisheye image circle spans the image height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective,
+    /// Parallel rays along the view direction; `height` is the view height in world units.
+    Orthographic { height: f32 },
+    Fisheye { mapping: FisheyeMapping },
+    /// Full-sphere panorama (longitude across, latitude down) kept level with `up`,
+    /// the 2:1 format 360° viewers expect.
+    Equirectangular,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// Two-camera rig for stereoscopic output.
+#[derive(Debug, Clone, Copy)]
+pub struct Stereo {
+    /// Distance between the eyes in world units.
+    pub eye_separation: f32,
+    /// Distance along the view axis where the eyes' lines of sight cross; objects
+    /// there appear at screen depth. Non-positive keeps the eyes parallel.
+    pub convergence: f32,
+}
+
+impl Stereo {
+    pub fn new(eye_separation: f32, convergence: f32) -> Self {
+        Stereo {
+            eye_separation,
+            convergence,
+        }
+    }
+}
+
+/// Exposure time for motion blur. Each primary ray picks a moment while the
+/// shutter is open; objects and the camera are traced where they are at that moment.
+#[derive(Debug, Clone, Copy)]
+pub struct Shutter {
+    /// Seconds the shutter stays open; 0 freezes motion.
+    pub duration: f32,
+    /// Rays per pixel spread over the exposure.
+    pub samples: u32,
+}
+
+impl Shutter {
+    pub fn instant() -> Self {
+        Shutter {
+            duration: 0.0,
+            samples: 1,
+        }
+    }
+
+    pub fn new(duration: f32, samples: u32) -> Self {
+        Shutter {
+            duration: duration.max(0.0),
+            samples: samples.max(1),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.duration > 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    /// Circles `center` and dollies towards it.
+    Orbit,
+    /// First-person: moves freely and turns `center` around the eye.
+    FreeFly,
+}
+
+#[derive(Clone)]
 pub struct Camera {
     pub eye: Vec3,
     pub center: Vec3,
     pub up: Vec3,
+    pub mode: CameraMode,
+    pub fov: FieldOfView,
+    pub projection: Projection,
+    pub lens: Lens,
+    /// Refocus on whatever is under the screen center whenever the camera moves.
+    pub autofocus: bool,
+    /// Omni-directional stereo: equirectangular rays start this far to the right
+    /// (negative: left) of the eye, perpendicular to each ray, instead of at the eye.
+    pub ods_offset: f32,
+    pub shutter: Shutter,
+    /// Where the camera is when the shutter opens and closes; without it the camera is still.
+    shutter_motion: Option<(CameraPose, CameraPose)>,
     has_changed: bool,
 }
 
@@ -14,21 +245,324 @@ impl Camera {
             eye,
             center,
             up,
+            mode: CameraMode::Orbit,
+            fov: FieldOfView::Vertical(PI / 3.0),
+            projection: Projection::Perspective,
+            lens: Lens::pinhole(),
+            autofocus: false,
+            ods_offset: 0.0,
+            shutter: Shutter::instant(),
+            shutter_motion: None,
             has_changed: true,
         }
     }
 
-    pub fn basis_change(&self, vector: &Vec3) -> Vec3 {
-        let forward = (self.center - self.eye).normalize();
+    /// Moves the camera to a pose sampled from a keyframed path.
+    pub fn set_pose(&mut self, pose: &CameraPose) {
+        self.eye = pose.position;
+        self.center = pose.target;
+        if let Some(fov) = pose.fov {
+            self.fov = FieldOfView::Vertical(fov);
+        }
+        if let Some(focus_distance) = pose.focus_distance {
+            self.lens.focus_distance = focus_distance.max(1e-3);
+        }
+        self.has_changed = true;
+    }
+
+    /// Current position and target. The field of view is left out so applying the
+    /// pose again keeps however the FOV was specified.
+    pub fn pose(&self) -> CameraPose {
+        CameraPose {
+            position: self.eye,
+            target: self.center,
+            fov: None,
+            focus_distance: Some(self.lens.focus_distance),
+        }
+    }
+
+    /// Moves the camera from `open` to `close` over the exposure, blurring the view
+    /// while the shutter is open. The camera itself is left at `close`.
+    pub fn set_shutter_motion(&mut self, open: CameraPose, close: CameraPose) {
+        self.set_pose(&close);
+        self.shutter_motion = Some((open, close));
+    }
+
+    /// Stops blurring along the last move; a view that was blurred is drawn again sharp.
+    pub fn clear_shutter_motion(&mut self) {
+        if self.shutter_motion.take().is_some() {
+            self.has_changed = true;
+        }
+    }
+
+    /// Eye and target `fraction` of the way through the exposure.
+    fn pose_at(&self, fraction: f32) -> (Vec3, Vec3) {
+        match &self.shutter_motion {
+            Some((open, close)) if self.shutter.is_open() => (
+                open.position.lerp(&close.position, fraction),
+                open.target.lerp(&close.target, fraction),
+            ),
+            _ => (self.eye, self.center),
+        }
+    }
+
+    /// Orthonormal `(forward, right, up)` for a camera at `eye` looking at `center`.
+    fn view_basis(&self, eye: &Vec3, center: &Vec3) -> (Vec3, Vec3, Vec3) {
+        let forward = (center - eye).normalize();
         let right = forward.cross(&self.up).normalize();
         let up = right.cross(&forward).normalize();
+        (forward, right, up)
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.has_changed = true;
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Self {
+        self.lens = lens;
+        self
+    }
+
+    pub fn set_aperture_radius(&mut self, aperture_radius: f32) {
+        self.lens.aperture_radius = aperture_radius.max(0.0);
+        self.has_changed = true;
+    }
 
-        let rotated = 
-        vector.x * right +
-        vector.y * up +
-        -vector.z * forward;
+    pub fn set_focus_distance(&mut self, focus_distance: f32) {
+        self.lens.focus_distance = focus_distance.max(1e-3);
+        self.has_changed = true;
+    }
+
+    /// Camera for one eye of a stereo pair. Perspective-style projections move the
+    /// eye sideways and toe it in towards the convergence point; an equirectangular
+    /// camera becomes an omni-directional stereo camera, where every ray is offset
+    /// from the eye so the panorama holds stereo in all directions.
+    pub fn eye_camera(&self, eye: Eye, stereo: &Stereo) -> Camera {
+        let sign = match eye {
+            Eye::Left => -1.0,
+            Eye::Right => 1.0,
+        };
+        let half_separation = sign * stereo.eye_separation * 0.5;
+
+        let mut camera = self.clone();
+        if self.projection == Projection::Equirectangular {
+            camera.ods_offset = half_separation;
+            return camera;
+        }
+
+        let forward = (self.center - self.eye).normalize();
+        let right = forward.cross(&self.up).normalize();
+        camera.eye = self.eye + right * half_separation;
+        camera.center = if stereo.convergence > 0.0 {
+            self.eye + forward * stereo.convergence
+        } else {
+            self.center + right * half_separation
+        };
+        camera
+    }
 
-        rotated.normalize()
+    /// Whether rays go through the thin lens; only the perspective projection models one.
+    fn uses_lens(&self) -> bool {
+        self.projection == Projection::Perspective && !self.lens.is_pinhole()
+    }
+
+    /// Primary rays traced per pixel: enough for both the lens and the shutter to be sampled.
+    pub fn samples_per_pixel(&self) -> u32 {
+        let lens_samples = if self.uses_lens() { self.lens.samples.max(1) } else { 1 };
+        let shutter_samples = if self.shutter.is_open() { self.shutter.samples.max(1) } else { 1 };
+        lens_samples.max(shutter_samples)
+    }
+
+    /// Focus distance that puts the first surface seen through pixel `(x, y)` in
+    /// focus, or `None` when that pixel only sees the sky.
+    pub fn focus_distance_at(
+        &self,
+        x: f32,
+        y: f32,
+        width: usize,
+        height: usize,
+        objects: &[&dyn RayIntersect],
+    ) -> Option<f32> {
+        let (origin, direction) = self.pinhole_ray(&self.eye, &self.center, x, y, width, height)?;
+        let closest = objects
+            .iter()
+            .map(|object| object.ray_intersect(&origin, &direction, 0.0))
+            .filter(|intersect| intersect.is_intersecting)
+            .map(|intersect| intersect.distance)
+            .fold(f32::INFINITY, f32::min);
+        if !closest.is_finite() {
+            return None;
+        }
+
+        // The focal plane is perpendicular to the view axis, so measure along it.
+        let forward = (self.center - self.eye).normalize();
+        Some(closest * direction.dot(&forward))
+    }
+
+    /// Widens (positive `delta`, radians) or narrows the view, keeping the way it was specified.
+    /// An orthographic view grows or shrinks its height instead.
+    pub fn adjust_fov(&mut self, delta: f32) {
+        if let Projection::Orthographic { height } = self.projection {
+            self.projection = Projection::Orthographic {
+                height: height * (1.0 + delta),
+            };
+            self.has_changed = true;
+            return;
+        }
+
+        self.fov = match self.fov {
+            FieldOfView::Vertical(fov) => FieldOfView::Vertical((fov + delta).clamp(MIN_FOV, MAX_FOV)),
+            FieldOfView::Horizontal(fov) => FieldOfView::Horizontal((fov + delta).clamp(MIN_FOV, MAX_FOV)),
+            FieldOfView::FocalLength {
+                focal_length: _,
+                sensor_height,
+            } => {
+                let fov = (self.fov.vertical(1.0) + delta).clamp(MIN_FOV, MAX_FOV);
+                FieldOfView::FocalLength {
+                    focal_length: sensor_height / (2.0 * (fov * 0.5).tan()),
+                    sensor_height,
+                }
+            }
+        };
+        self.has_changed = true;
+    }
+
+    /// Half-height of the image plane at unit distance in front of the eye.
+    fn perspective_scale(&self, aspect_ratio: f32) -> f32 {
+        (self.fov.vertical(aspect_ratio) * 0.5).tan()
+    }
+
+    /// Footprint of one pixel's primary ray: the width it starts with and how fast it spreads.
+    pub fn pixel_cone(&self, width: usize, height: usize) -> RayCone {
+        let aspect_ratio = width as f32 / height as f32;
+        match self.projection {
+            Projection::Perspective => {
+                RayCone::new(0.0, 2.0 * self.perspective_scale(aspect_ratio) / height as f32)
+            }
+            Projection::Orthographic { height: view_height } => {
+                RayCone::new(view_height / height as f32, 0.0)
+            }
+            Projection::Fisheye { .. } => {
+                RayCone::new(0.0, self.fov.vertical(aspect_ratio) / height as f32)
+            }
+            Projection::Equirectangular => RayCone::new(0.0, PI / height as f32),
+        }
+    }
+
+    /// World-space origin and unit direction of the primary ray through pixel
+    /// coordinates `(x, y)` of a `width` x `height` image, with `y` growing downwards,
+    /// taken `shutter_fraction` (0..1) of the way through the exposure.
+    /// With an open aperture each call picks a new point on the lens. `None` for
+    /// pixels outside the image circle of a fisheye.
+    pub fn generate_ray(
+        &self,
+        x: f32,
+        y: f32,
+        width: usize,
+        height: usize,
+        shutter_fraction: f32,
+    ) -> Option<(Vec3, Vec3)> {
+        let (eye, center) = self.pose_at(shutter_fraction);
+        let (origin, direction) = self.pinhole_ray(&eye, &center, x, y, width, height)?;
+        if !self.uses_lens() {
+            return Some((origin, direction));
+        }
+
+        let (forward, right, up) = self.view_basis(&eye, &center);
+        let focus_point = origin + direction * (self.lens.focus_distance / direction.dot(&forward));
+        let lens_point = self.lens.bokeh.sample() * self.lens.aperture_radius;
+        let origin = origin + right * lens_point.x + up * lens_point.y;
+        Some((origin, (focus_point - origin).normalize()))
+    }
+
+    fn pinhole_ray(
+        &self,
+        eye: &Vec3,
+        center: &Vec3,
+        x: f32,
+        y: f32,
+        width: usize,
+        height: usize,
+    ) -> Option<(Vec3, Vec3)> {
+        let aspect_ratio = width as f32 / height as f32;
+
+        let screen_x = (2.0 * x) / width as f32 - 1.0;
+        let screen_y = -(2.0 * y) / height as f32 + 1.0;
+
+        let (forward, right, up) = self.view_basis(eye, center);
+        let to_world = |v: Vec3| (right * v.x + up * v.y - forward * v.z).normalize();
+
+        match self.projection {
+            Projection::Perspective => {
+                let perspective_scale = self.perspective_scale(aspect_ratio);
+                let screen_x = screen_x * aspect_ratio * perspective_scale;
+                let screen_y = screen_y * perspective_scale;
+
+                Some((*eye, to_world(Vec3::new(screen_x, screen_y, -1.0))))
+            }
+            Projection::Orthographic { height: view_height } => {
+                let half_height = view_height * 0.5;
+                let offset = Vec3::new(screen_x * aspect_ratio * half_height, screen_y * half_height, 0.0);
+                Some((eye + right * offset.x + up * offset.y, forward))
+            }
+            Projection::Fisheye { mapping } => {
+                let (px, py) = (screen_x * aspect_ratio, screen_y);
+                let radius = (px * px + py * py).sqrt();
+                if radius > 1.0 {
+                    return None;
+                }
+
+                let max_angle = self.fov.vertical(aspect_ratio) * 0.5;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => radius * max_angle,
+                    FisheyeMapping::Equisolid => {
+                        2.0 * (radius * (max_angle * 0.5).sin()).clamp(-1.0, 1.0).asin()
+                    }
+                };
+                let phi = py.atan2(px);
+                let ray_direction = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    -theta.cos(),
+                );
+                Some((*eye, to_world(ray_direction)))
+            }
+            Projection::Equirectangular => {
+                let longitude = screen_x * PI;
+                let latitude = screen_y * PI * 0.5;
+                let (forward, right, up) = self.level_basis(eye, center);
+                let direction = forward * (latitude.cos() * longitude.cos())
+                    + right * (latitude.cos() * longitude.sin())
+                    + up * latitude.sin();
+                let direction = direction.normalize();
+
+                let tangent = direction.cross(&up);
+                let origin = if self.ods_offset != 0.0 && tangent.magnitude_squared() > 1e-8 {
+                    eye + tangent.normalize() * self.ods_offset
+                } else {
+                    *eye
+                };
+                Some((origin, direction))
+            }
+        }
+    }
+
+    /// View basis with the pitch removed, so panoramas keep the horizon straight.
+    fn level_basis(&self, eye: &Vec3, center: &Vec3) -> (Vec3, Vec3, Vec3) {
+        let up = self.up.normalize();
+        let view = center - eye;
+        let mut forward = view - up * view.dot(&up);
+        if forward.magnitude_squared() < 1e-8 {
+            // Looking straight up or down: any horizontal direction will do.
+            forward = up.cross(&Vec3::new(1.0, 0.0, 0.0));
+            if forward.magnitude_squared() < 1e-8 {
+                forward = up.cross(&Vec3::new(0.0, 0.0, 1.0));
+            }
+        }
+        let forward = forward.normalize();
+        let right = forward.cross(&up).normalize();
+        (forward, right, up)
     }
 
     pub fn orbit(&mut self, delta_yaw: f32, delta_pitch: f32) {
@@ -53,6 +587,33 @@ impl Camera {
         self.has_changed = true;
     }
 
+    /// Moves eye and target together, along the view direction, to the right and straight up.
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32) {
+        let view = (self.center - self.eye).normalize();
+        let side = view.cross(&self.up).normalize();
+        let offset = view * forward + side * right + self.up.normalize() * up;
+        self.eye += offset;
+        self.center += offset;
+        self.has_changed = true;
+    }
+
+    /// Turns the view around the eye; positive yaw looks right, positive pitch looks up.
+    pub fn look(&mut self, delta_yaw: f32, delta_pitch: f32) {
+        let view = self.center - self.eye;
+        let distance = view.magnitude();
+
+        let yaw = view.z.atan2(view.x) + delta_yaw;
+        let pitch = ((view.y / distance).asin() + delta_pitch).clamp(-PI / 2.0 + 0.1, PI / 2.0 - 0.1);
+
+        self.center = self.eye
+            + Vec3::new(
+                distance * yaw.cos() * pitch.cos(),
+                distance * pitch.sin(),
+                distance * yaw.sin() * pitch.cos(),
+            );
+        self.has_changed = true;
+    }
+
     pub fn zoom(&mut self, delta: f32) {
         let direction = (self.center - self.eye).normalize();
         self.eye += direction * delta;
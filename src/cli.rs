@@ -0,0 +1,59 @@
+use std::ops::Range;
+
+const USAGE: &str = "usage: Raytracing [--scene <file.toml>] [--seed <n>] [--frames <first>..<end> [--output <dir>]] [--export <file.obj|file.ply>]";
+
+/// Command-line options. Without `--frames` the scene opens in a window; with it,
+/// frames `first` up to (not including) `end` are rendered to numbered PNGs.
+/// `--export` writes the scene's geometry to a model file instead.
+#[derive(Debug)]
+pub struct Options {
+    pub scene: Option<String>,
+    /// Overrides the scene file's seed.
+    pub seed: Option<u64>,
+    pub frames: Option<Range<u32>>,
+    pub output: String,
+    pub export: Option<String>,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        let mut options = Options {
+            scene: None,
+            seed: None,
+            frames: None,
+            output: "frames".to_string(),
+            export: None,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--scene" => options.scene = Some(value()?),
+                "--seed" => {
+                    let text = value()?;
+                    let seed = text.parse().map_err(|_| format!("invalid seed '{}'", text))?;
+                    options.seed = Some(seed);
+                }
+                "--output" => options.output = value()?,
+                "--export" => options.export = Some(value()?),
+                "--frames" => options.frames = Some(parse_frame_range(&value()?)?),
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ => return Err(format!("unknown argument '{}'\n{}", arg, USAGE)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_frame_range(text: &str) -> Result<Range<u32>, String> {
+    let invalid = || format!("invalid frame range '{}', expected e.g. 0..120", text);
+    let (first, end) = text.split_once("..").ok_or_else(invalid)?;
+    let first: u32 = first.trim().parse().map_err(|_| invalid())?;
+    let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+    if end <= first {
+        return Err(invalid());
+    }
+    Ok(first..end)
+}
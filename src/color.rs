@@ -1,6 +1,7 @@
+use nalgebra_glm::Vec3;
 use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -23,13 +24,36 @@ impl Color {
         Color { r: 0, g: 0, b: 0 }
     }
 
-    pub fn to_hex(&self) -> u32 {
+    pub fn to_hex(self) -> u32 {
         ((self.r as u32) << 16) | ((self.g as u32) << 8) | (self.b as u32)
     }
 
     pub fn is_black(&self) -> bool {
         self.r == 0 && self.g == 0 && self.b == 0
     }
+
+    /// Perceived brightness in `[0, 1]`.
+    pub fn luminance(self) -> f32 {
+        (0.2126 * self.r as f32 + 0.7152 * self.g as f32 + 0.0722 * self.b as f32) / 255.0
+    }
+
+    /// Channels normalized to `[0, 1]`.
+    pub fn to_vec3(self) -> Vec3 {
+        Vec3::new(
+            self.r as f32 / 255.0,
+            self.g as f32 / 255.0,
+            self.b as f32 / 255.0,
+        )
+    }
+
+    /// Inverse of `to_vec3`, clamping to the displayable range.
+    pub fn from_vec3(v: Vec3) -> Self {
+        Color {
+            r: (v.x * 255.0).round().clamp(0.0, 255.0) as u8,
+            g: (v.y * 255.0).round().clamp(0.0, 255.0) as u8,
+            b: (v.z * 255.0).round().clamp(0.0, 255.0) as u8,
+        }
+    }
 }
 
 use std::ops::Add;
@@ -70,6 +94,19 @@ impl Mul<f32> for Color {
     }
 }
 
+impl Mul<Vec3> for Color {
+    type Output = Color;
+
+    /// Scales each channel by the matching component, e.g. a per-channel transmittance.
+    fn mul(self, factor: Vec3) -> Color {
+        Color {
+            r: (self.r as f32 * factor.x).clamp(0.0, 255.0) as u8,
+            g: (self.g as f32 * factor.y).clamp(0.0, 255.0) as u8,
+            b: (self.b as f32 * factor.z).clamp(0.0, 255.0) as u8,
+        }
+    }
+}
+
 impl fmt::Display for Color {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Color(r: {}, g: {}, b: {})", self.r, self.g, self.b)
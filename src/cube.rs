@@ -1,24 +1,193 @@
-use nalgebra_glm::Vec3;
+use nalgebra_glm::{Mat3, Vec3};
 use crate::material::Material;
 use crate::ray_intersect::{Intersect, RayIntersect};
 
+/// The six faces of a cube, named by their outward normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CubeFace {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl CubeFace {
+    /// Every face, in `index` order.
+    pub const ALL: [CubeFace; 6] = [
+        CubeFace::PosX,
+        CubeFace::NegX,
+        CubeFace::PosY,
+        CubeFace::NegY,
+        CubeFace::PosZ,
+        CubeFace::NegZ,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn normal(self) -> Vec3 {
+        match self {
+            CubeFace::PosX => Vec3::new(1.0, 0.0, 0.0),
+            CubeFace::NegX => Vec3::new(-1.0, 0.0, 0.0),
+            CubeFace::PosY => Vec3::new(0.0, 1.0, 0.0),
+            CubeFace::NegY => Vec3::new(0.0, -1.0, 0.0),
+            CubeFace::PosZ => Vec3::new(0.0, 0.0, 1.0),
+            CubeFace::NegZ => Vec3::new(0.0, 0.0, -1.0),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Cube {
     pub min: Vec3,
     pub max: Vec3,
     pub material: Material,
+    /// Optional per-face materials indexed by `CubeFace::index`; `material` is used otherwise.
+    pub face_materials: Option<Box<[Material; 6]>>,
+}
+
+impl Cube {
+    pub fn new(min: Vec3, max: Vec3, material: Material) -> Self {
+        Cube {
+            min,
+            max,
+            material,
+            face_materials: None,
+        }
+    }
+
+    /// A cube with its own material on each face, ordered +X, -X, +Y, -Y, +Z, -Z.
+    pub fn with_face_materials(min: Vec3, max: Vec3, materials: [Material; 6]) -> Self {
+        Cube {
+            min,
+            max,
+            material: materials[CubeFace::PosY.index()].clone(),
+            face_materials: Some(Box::new(materials)),
+        }
+    }
+
+    /// Grass-block style shorthand: one material for the top, one for the four sides and one for the bottom.
+    pub fn with_top_side_bottom(
+        min: Vec3,
+        max: Vec3,
+        top: Material,
+        side: Material,
+        bottom: Material,
+    ) -> Self {
+        Cube::with_face_materials(
+            min,
+            max,
+            [side.clone(), side.clone(), top, bottom, side.clone(), side],
+        )
+    }
+
+    /// Whether any face changes over time, e.g. an animated texture or flickering emission.
+    pub fn is_animated(&self) -> bool {
+        match &self.face_materials {
+            Some(materials) => materials.iter().any(|material| material.is_animated()),
+            None => self.material.is_animated(),
+        }
+    }
+
+    pub fn face_material(&self, face: CubeFace) -> &Material {
+        match &self.face_materials {
+            Some(materials) => &materials[face.index()],
+            None => &self.material,
+        }
+    }
+
+    /// The four corners of `face` with the texture coordinates a ray hitting them
+    /// gets once the cube is placed by `linear`, counter-clockwise as seen from
+    /// outside, starting at `(u, v) = (0, 0)` before the material's UV transform.
+    pub fn face_vertices(&self, face: CubeFace, linear: &Mat3) -> [(Vec3, f32, f32); 4] {
+        let normal = face.normal();
+        let mut vertices = [(Vec3::zeros(), 0.0, 0.0); 4];
+        for corner in 0..8 {
+            let pick = |bit: usize, axis: usize| if corner & bit != 0 { self.max[axis] } else { self.min[axis] };
+            let point = Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2));
+            let on_face = (0..3).all(|axis| match normal[axis] {
+                n if n > 0.0 => point[axis] == self.max[axis],
+                n if n < 0.0 => point[axis] == self.min[axis],
+                _ => true,
+            });
+            if !on_face {
+                continue;
+            }
+
+            let (u, v, dpdu, dpdv) = self.face_uv(face, &point);
+            let slot = match (u > 0.5, v > 0.5) {
+                (false, false) => 0,
+                (true, false) => 1,
+                (true, true) => 2,
+                (false, true) => 3,
+            };
+            let mut placed = Intersect::new(point, normal, 0.0, self.face_material(face).clone(), u, v)
+                .with_surface_derivatives(dpdu, dpdv)
+                .apply_uv_transform();
+            placed.transform_surface(linear);
+            vertices[slot] = (point, placed.u, placed.v);
+        }
+        vertices
+    }
+
+    fn hit(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32, t: f32, face: CubeFace) -> Intersect {
+        let intersect_point = ray_origin + ray_direction * t;
+        let (u, v, dpdu, dpdv) = self.face_uv(face, &intersect_point);
+
+        Intersect::new(
+            intersect_point,
+            face.normal(),
+            t,
+            self.face_material(face).clone(),
+            u,
+            v,
+        )
+        .with_surface_derivatives(dpdu, dpdv)
+        .with_time(time)
+        .with_object_point(intersect_point - self.min)
+        .apply_uv_transform()
+    }
+
+    /// Texture coordinates of `point` on `face`, with `dP/du` and `dP/dv`. Side faces
+    /// are laid out as seen from outside with `v` pointing up, so textures line up
+    /// around the cube; the top has `v` pointing towards -Z and the bottom towards +Z.
+    fn face_uv(&self, face: CubeFace, point: &Vec3) -> (f32, f32, Vec3, Vec3) {
+        let size = self.max - self.min;
+        let rel = point - self.min;
+        let x = rel.x / size.x;
+        let y = rel.y / size.y;
+        let z = rel.z / size.z;
+
+        match face {
+            CubeFace::PosZ => (x, y, Vec3::new(size.x, 0.0, 0.0), Vec3::new(0.0, size.y, 0.0)),
+            CubeFace::NegZ => (1.0 - x, y, Vec3::new(-size.x, 0.0, 0.0), Vec3::new(0.0, size.y, 0.0)),
+            CubeFace::PosX => (1.0 - z, y, Vec3::new(0.0, 0.0, -size.z), Vec3::new(0.0, size.y, 0.0)),
+            CubeFace::NegX => (z, y, Vec3::new(0.0, 0.0, size.z), Vec3::new(0.0, size.y, 0.0)),
+            CubeFace::PosY => (x, 1.0 - z, Vec3::new(size.x, 0.0, 0.0), Vec3::new(0.0, 0.0, -size.z)),
+            CubeFace::NegY => (x, z, Vec3::new(size.x, 0.0, 0.0), Vec3::new(0.0, 0.0, size.z)),
+        }
+    }
 }
 
 impl RayIntersect for Cube {
-    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3) -> Intersect {
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect {
         let inv_dir = Vec3::new(1.0 / ray_direction.x, 1.0 / ray_direction.y, 1.0 / ray_direction.z);
-        
-        let mut tmin = (self.min.x - ray_origin.x) * inv_dir.x;
-        let mut tmax = (self.max.x - ray_origin.x) * inv_dir.x;
-        if tmin > tmax { std::mem::swap(&mut tmin, &mut tmax); }
 
-        let mut tymin = (self.min.y - ray_origin.y) * inv_dir.y;
-        let mut tymax = (self.max.y - ray_origin.y) * inv_dir.y;
-        if tymin > tymax { std::mem::swap(&mut tymin, &mut tymax); }
+        // Entry and exit distances for one axis. A ray parallel to the slab that starts
+        // exactly on one of its planes gives 0 * inf = NaN; count it as inside the slab.
+        let slab = |min: f32, max: f32, origin: f32, inv: f32| {
+            let (mut t0, mut t1) = ((min - origin) * inv, (max - origin) * inv);
+            if t0 > t1 { std::mem::swap(&mut t0, &mut t1); }
+            if t0.is_nan() { t0 = f32::NEG_INFINITY; }
+            if t1.is_nan() { t1 = f32::INFINITY; }
+            (t0, t1)
+        };
+
+        let (mut tmin, mut tmax) = slab(self.min.x, self.max.x, ray_origin.x, inv_dir.x);
+        let (tymin, tymax) = slab(self.min.y, self.max.y, ray_origin.y, inv_dir.y);
 
         if (tmin > tymax) || (tymin > tmax) {
             return Intersect::empty();
@@ -31,9 +200,7 @@ impl RayIntersect for Cube {
             tmax = tymax;
         }
 
-        let mut tzmin = (self.min.z - ray_origin.z) * inv_dir.z;
-        let mut tzmax = (self.max.z - ray_origin.z) * inv_dir.z;
-        if tzmin > tzmax { std::mem::swap(&mut tzmin, &mut tzmax); }
+        let (tzmin, tzmax) = slab(self.min.z, self.max.z, ray_origin.z, inv_dir.z);
 
         if (tmin > tzmax) || (tzmin > tmax) {
             return Intersect::empty();
@@ -46,28 +213,42 @@ impl RayIntersect for Cube {
             tmax = tzmax;
         }
 
-        
-        let intersect_point = ray_origin + ray_direction * tmin;
-        let mut normal = Vec3::new(0.0, 0.0, 0.0);
-        let mut u = 0.0;
-        let mut v = 0.0;
-        
-        
-        if tmin == tzmin {
-            normal.z = if ray_direction.z < 0.0 { 1.0 } else { -1.0 };
-            u = (intersect_point.x - self.min.x) / (self.max.x - self.min.x);
-            v = (intersect_point.y - self.min.y) / (self.max.y - self.min.y);
-        } else if tmin == tymin {
-            normal.y = if ray_direction.y < 0.0 { 1.0 } else { -1.0 };
-            u = (intersect_point.x - self.min.x) / (self.max.x - self.min.x);
-            v = (intersect_point.z - self.min.z) / (self.max.z - self.min.z);
-        } else {
-            normal.x = if ray_direction.x < 0.0 { 1.0 } else { -1.0 };
-            u = (intersect_point.z - self.min.z) / (self.max.z - self.min.z);
-            v = (intersect_point.y - self.min.y) / (self.max.y - self.min.y);
-        }
-
-        Intersect::new(intersect_point, normal, tmin, self.material.clone(), u, v)
+        if tmax < 0.0 {
+            return Intersect::empty();
+        }
+
+        // A ray starting inside the cube (e.g. a refracted ray) leaves through the far face.
+        let inside = tmin < 0.0;
+        let t = if inside { tmax } else { tmin };
+
+        let face_at = |t: f32, exiting: bool| {
+            if t == tzmin || t == tzmax {
+                let positive = (ray_direction.z < 0.0) != exiting;
+                if positive { CubeFace::PosZ } else { CubeFace::NegZ }
+            } else if t == tymin || t == tymax {
+                let positive = (ray_direction.y < 0.0) != exiting;
+                if positive { CubeFace::PosY } else { CubeFace::NegY }
+            } else {
+                let positive = (ray_direction.x < 0.0) != exiting;
+                if positive { CubeFace::PosX } else { CubeFace::NegX }
+            }
+        };
+
+        let intersect = self.hit(ray_origin, ray_direction, time, t, face_at(t, inside));
+        if !intersect.material.is_cut_out(intersect.u, intersect.v, intersect.time) {
+            return intersect;
+        }
+        if inside {
+            return Intersect::empty();
+        }
+
+        // Seen through a cut-out texel, the ray goes on to the inside of the far face.
+        let mut far = self.hit(ray_origin, ray_direction, time, tmax, face_at(tmax, true));
+        if far.material.is_cut_out(far.u, far.v, far.time) {
+            return Intersect::empty();
+        }
+        far.normal = -far.normal;
+        far
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
@@ -0,0 +1,538 @@
+use nalgebra_glm::{Mat3, Mat4, Vec2, Vec3, Vec4};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cube::{Cube, CubeFace};
+use crate::material::Material;
+use crate::mesh::{Mesh, Triangle as MeshTriangle};
+use crate::ray_intersect::Intersect;
+use crate::scene_graph::{SceneNode, Shape};
+use crate::texture::{Texture, TextureSource};
+
+/// Brightness the scene's emission intensities are measured against: glowstone's.
+const EMISSION_SCALE: f32 = 0.3;
+
+/// A triangle in world space, counter-clockwise as seen from outside.
+struct Triangle {
+    positions: [Vec3; 3],
+    normals: [Vec3; 3],
+    uvs: [Vec2; 3],
+    material: usize,
+}
+
+/// Scene geometry baked into triangles, grouped by scene node, ready to write out.
+pub struct SceneExport {
+    groups: Vec<(String, Vec<Triangle>)>,
+    materials: Vec<Material>,
+}
+
+/// Writes the visible geometry under `root` to `path`: Wavefront `.obj` with a
+/// `.mtl` beside it, or `.ply`. Moving nodes are written where they rest.
+pub fn export(root: &SceneNode, path: &str) -> Result<(), String> {
+    let scene = SceneExport::new(root);
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("obj") => scene.write_obj(path),
+        Some("ply") => scene.write_ply(path),
+        _ => Err(format!("can't export to {}: use a .obj or .ply file", path)),
+    }
+}
+
+impl SceneExport {
+    /// Every block becomes the 12 triangles of its faces, with the texture
+    /// coordinates rays see; imported meshes keep their own triangles.
+    pub fn new(root: &SceneNode) -> Self {
+        let mut scene = SceneExport {
+            groups: Vec::new(),
+            materials: Vec::new(),
+        };
+        let mut materials = HashMap::new();
+        scene.add_node(root, &Mat4::identity(), None, &mut materials);
+        scene
+    }
+
+    fn add_node(
+        &mut self,
+        node: &SceneNode,
+        parent: &Mat4,
+        material: Option<&Material>,
+        materials: &mut HashMap<String, usize>,
+    ) {
+        if !node.visible {
+            return;
+        }
+
+        let world = parent * node.transform;
+        let material = node.material.as_ref().or(material);
+        let mut triangles = Vec::new();
+        for shape in &node.shapes {
+            match shape {
+                Shape::Block(cube) => {
+                    let mut cube = cube.clone();
+                    if let Some(material) = material {
+                        cube.material = material.clone();
+                        cube.face_materials = None;
+                    }
+                    self.add_cube(&cube, &world, materials, &mut triangles);
+                }
+                Shape::Shared(object) => {
+                    if let Some(cube) = object.as_any().downcast_ref::<Cube>() {
+                        self.add_cube(cube, &world, materials, &mut triangles);
+                    } else if let Some(mesh) = object.as_any().downcast_ref::<Mesh>() {
+                        self.add_mesh(mesh, &world, materials, &mut triangles);
+                    } else {
+                        eprintln!(
+                            "warning: node '{}': left a shared object out of the export; only blocks and meshes are written",
+                            node.name
+                        );
+                    }
+                }
+            }
+        }
+        if !triangles.is_empty() {
+            self.groups.push((node.name.clone(), triangles));
+        }
+
+        for child in &node.children {
+            self.add_node(child, &world, material, materials);
+        }
+    }
+
+    fn add_cube(
+        &mut self,
+        cube: &Cube,
+        world: &Mat4,
+        materials: &mut HashMap<String, usize>,
+        triangles: &mut Vec<Triangle>,
+    ) {
+        let normal_matrix = normal_matrix(world);
+        let linear: Mat3 = world.fixed_view::<3, 3>(0, 0).into();
+        for face in CubeFace::ALL {
+            let material = cube.face_material(face);
+            let index = self.material_index(material, materials);
+            let corners = cube.face_vertices(face, &linear).map(|(point, u, v)| {
+                let (u, v) = atlas_uv(material, u, v);
+                (transform_point(world, &point), Vec2::new(u, v))
+            });
+            let normal = (normal_matrix * face.normal()).normalize();
+            for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+                triangles.push(Triangle {
+                    positions: [corners[a].0, corners[b].0, corners[c].0],
+                    normals: [normal; 3],
+                    uvs: [corners[a].1, corners[b].1, corners[c].1],
+                    material: index,
+                });
+            }
+        }
+    }
+
+    fn add_mesh(
+        &mut self,
+        mesh: &Mesh,
+        world: &Mat4,
+        materials: &mut HashMap<String, usize>,
+        triangles: &mut Vec<Triangle>,
+    ) {
+        let normal_matrix = normal_matrix(world);
+        let linear: Mat3 = world.fixed_view::<3, 3>(0, 0).into();
+        let index = self.material_index(&mesh.material, materials);
+        for triangle in mesh.triangles() {
+            let positions = triangle.positions.map(|point| transform_point(world, &point));
+            let face_normal = (positions[1] - positions[0]).cross(&(positions[2] - positions[0]));
+            let normals = match triangle.normals {
+                Some(normals) => normals.map(|normal| (normal_matrix * normal).normalize()),
+                None => [face_normal.normalize(); 3],
+            };
+            triangles.push(Triangle {
+                positions,
+                normals,
+                uvs: mesh_uvs(&mesh.material, triangle, &linear),
+                material: index,
+            });
+        }
+    }
+
+    /// Materials that would be written out the same are stored once.
+    fn material_index(&mut self, material: &Material, materials: &mut HashMap<String, usize>) -> usize {
+        let key = material_key(material);
+        *materials.entry(key).or_insert_with(|| {
+            self.materials.push(material.clone());
+            self.materials.len() - 1
+        })
+    }
+
+    /// Writes `path` and a material library with the same name ending in `.mtl`.
+    /// Every texture is saved as a PNG beside the model, see `TextureFiles`.
+    pub fn write_obj(&self, path: &str) -> Result<(), String> {
+        let path = Path::new(path);
+        let library = path.with_extension("mtl");
+        let library_name = file_name(&library);
+        let mut textures = TextureFiles::new(path);
+
+        let mut mtl = String::new();
+        for (index, material) in self.materials.iter().enumerate() {
+            let _ = writeln!(mtl, "newmtl material_{}", index);
+            mtl.push_str(&mtl_entry(material, &mut textures)?);
+            mtl.push('\n');
+        }
+
+        let mut obj = String::new();
+        let _ = writeln!(obj, "mtllib {}", library_name);
+        let mut next_vertex = 1;
+        for (name, triangles) in &self.groups {
+            let _ = writeln!(obj, "o {}", name.replace(char::is_whitespace, "_"));
+            let mut material = None;
+            for triangle in triangles {
+                for i in 0..3 {
+                    let (p, n, uv) = (triangle.positions[i], triangle.normals[i], triangle.uvs[i]);
+                    let _ = writeln!(obj, "v {} {} {}", p.x, p.y, p.z);
+                    let _ = writeln!(obj, "vt {} {}", uv.x, uv.y);
+                    let _ = writeln!(obj, "vn {} {} {}", n.x, n.y, n.z);
+                }
+                if material != Some(triangle.material) {
+                    material = Some(triangle.material);
+                    let _ = writeln!(obj, "usemtl material_{}", triangle.material);
+                }
+                let [a, b, c] = [next_vertex, next_vertex + 1, next_vertex + 2];
+                let _ = writeln!(obj, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}");
+                next_vertex += 3;
+            }
+        }
+
+        write_file(&library, &mtl)?;
+        write_file(path, &obj)
+    }
+
+    /// Writes an ASCII PLY with per-vertex normals, texture coordinates and colors.
+    /// Diffuse textures are listed as `TextureFile` comments, which each face refers
+    /// to by `texnumber` (-1 for none), as MeshLab reads them.
+    pub fn write_ply(&self, path: &str) -> Result<(), String> {
+        let path = Path::new(path);
+        let mut textures = TextureFiles::new(path);
+        let mut texture_numbers = Vec::with_capacity(self.materials.len());
+        let mut texture_files: Vec<String> = Vec::new();
+        for material in &self.materials {
+            let number = match image_texture(material.texture.as_ref()) {
+                Some(texture) => {
+                    let file = textures.reference(texture)?;
+                    match texture_files.iter().position(|known| *known == file) {
+                        Some(number) => number as i32,
+                        None => {
+                            texture_files.push(file);
+                            texture_files.len() as i32 - 1
+                        }
+                    }
+                }
+                None => -1,
+            };
+            texture_numbers.push(number);
+        }
+
+        let count: usize = self.groups.iter().map(|(_, triangles)| triangles.len()).sum();
+        let mut ply = String::new();
+        ply.push_str("ply\nformat ascii 1.0\n");
+        for file in &texture_files {
+            let _ = writeln!(ply, "comment TextureFile {}", file);
+        }
+        let _ = writeln!(ply, "element vertex {}", count * 3);
+        for property in ["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+            let _ = writeln!(ply, "property float {}", property);
+        }
+        ply.push_str("property uchar red\nproperty uchar green\nproperty uchar blue\n");
+        let _ = writeln!(ply, "element face {}", count);
+        ply.push_str("property list uchar int vertex_indices\nproperty int texnumber\nend_header\n");
+
+        let triangles = self.groups.iter().flat_map(|(_, triangles)| triangles);
+        for triangle in triangles.clone() {
+            let material = &self.materials[triangle.material];
+            // Textured materials are white so the texture shows its own colors.
+            let color = if material.has_texture {
+                [255, 255, 255]
+            } else {
+                [material.diffuse.r, material.diffuse.g, material.diffuse.b]
+            };
+            for i in 0..3 {
+                let (p, n, uv) = (triangle.positions[i], triangle.normals[i], triangle.uvs[i]);
+                let _ = writeln!(
+                    ply,
+                    "{} {} {} {} {} {} {} {} {} {} {}",
+                    p.x, p.y, p.z, n.x, n.y, n.z, uv.x, uv.y, color[0], color[1], color[2]
+                );
+            }
+        }
+        for (index, triangle) in triangles.enumerate() {
+            let first = index * 3;
+            let texture = texture_numbers[triangle.material];
+            let _ = writeln!(ply, "3 {} {} {} {}", first, first + 1, first + 2, texture);
+        }
+
+        write_file(path, &ply)
+    }
+}
+
+/// File names for the textures an export refers to. Every texture is saved as a PNG
+/// beside the export and referred to by its bare name, so the export can be moved
+/// or shared along with its textures. Animated strips are saved as their first frame.
+struct TextureFiles {
+    directory: PathBuf,
+    stem: String,
+    files: HashMap<usize, String>,
+    names: HashSet<String>,
+}
+
+impl TextureFiles {
+    fn new(export: &Path) -> Self {
+        TextureFiles {
+            directory: export.parent().map(Path::to_path_buf).unwrap_or_default(),
+            stem: export
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            files: HashMap::new(),
+            names: HashSet::new(),
+        }
+    }
+
+    fn reference(&mut self, texture: &Arc<Texture>) -> Result<String, String> {
+        let key = Arc::as_ptr(texture) as usize;
+        if let Some(file) = self.files.get(&key) {
+            return Ok(file.clone());
+        }
+
+        // Named after the source file where there is one, e.g. `scene_stone.png`.
+        let source = texture
+            .path
+            .as_deref()
+            .and_then(|path| Path::new(path).file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "texture".to_string());
+        let mut file = format!("{}_{}.png", self.stem, source);
+        let mut copy = 1;
+        while self.names.contains(&file) {
+            file = format!("{}_{}_{}.png", self.stem, source, copy);
+            copy += 1;
+        }
+
+        let target = self.directory.join(&file);
+        texture
+            .image()
+            .crop_imm(0, 0, texture.width as u32, texture.height as u32)
+            .save(&target)
+            .map_err(|e| format!("could not write {}: {}", target.display(), e))?;
+        self.names.insert(file.clone());
+        self.files.insert(key, file.clone());
+        Ok(file)
+    }
+}
+
+/// The `.mtl` statements for a material, mapping the renderer's Phong weights back
+/// onto the classic illumination model.
+fn mtl_entry(material: &Material, textures: &mut TextureFiles) -> Result<String, String> {
+    let [diffuse, specular, reflectivity, transparency] = material.albedo;
+    let mut entry = String::new();
+
+    let kd = if material.has_texture {
+        Vec3::new(diffuse, diffuse, diffuse)
+    } else {
+        material.diffuse.to_vec3() * diffuse
+    };
+    let ke = material.emission_color.to_vec3() * (material.emission_intensity / EMISSION_SCALE);
+    let illum = if transparency > 0.0 {
+        6
+    } else if reflectivity > 0.0 {
+        3
+    } else {
+        2
+    };
+    let _ = writeln!(entry, "Kd {} {} {}", kd.x, kd.y, kd.z);
+    let _ = writeln!(entry, "Ks {} {} {}", specular, specular, specular);
+    let _ = writeln!(entry, "Ke {} {} {}", ke.x, ke.y, ke.z);
+    let _ = writeln!(entry, "Ns {}", material.specular);
+    // Mirror-like reflection goes in the PBR extension's metallic term.
+    let _ = writeln!(entry, "Pm {}", reflectivity);
+    let _ = writeln!(entry, "Ni {}", material.refractive_index);
+    let _ = writeln!(entry, "d {}", 1.0 - transparency);
+    let _ = writeln!(entry, "illum {}", illum);
+
+    let maps = [
+        ("map_Kd", material.texture.as_ref()),
+        ("map_Bump", material.normal_map.as_ref()),
+        ("map_Pr", material.roughness_map.as_ref()),
+        ("disp", material.height_map.as_ref()),
+    ];
+    for (statement, source) in maps {
+        if let Some(texture) = image_texture(source) {
+            let _ = writeln!(entry, "{} {}", statement, textures.reference(texture)?);
+        }
+    }
+    // The alpha test reads the diffuse texture's alpha channel.
+    if material.alpha_cutoff.is_some() {
+        if let Some(texture) = image_texture(material.texture.as_ref()) {
+            let _ = writeln!(entry, "map_d {}", textures.reference(texture)?);
+        }
+    }
+    Ok(entry)
+}
+
+/// Identifies materials by everything the export writes about them.
+fn material_key(material: &Material) -> String {
+    let texture = |source: Option<&TextureSource>| image_texture(source).map(|texture| Arc::as_ptr(texture) as usize);
+    format!(
+        "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+        material.diffuse.to_vec3(),
+        material.specular,
+        material.albedo,
+        material.refractive_index,
+        material.emission_color.to_vec3(),
+        material.emission_intensity,
+        material.alpha_cutoff.is_some(),
+        material.has_texture,
+        material.atlas_tile.map(|tile| [tile.u_min, tile.v_min, tile.u_max, tile.v_max]),
+        texture(material.texture.as_ref()),
+        texture(material.normal_map.as_ref()),
+        texture(material.roughness_map.as_ref()),
+        texture(material.height_map.as_ref()),
+    )
+}
+
+/// A mesh triangle's texture coordinates after its material's uv transform, as rays
+/// see them once the mesh is placed by `linear`.
+fn mesh_uvs(material: &Material, triangle: &MeshTriangle, linear: &Mat3) -> [Vec2; 3] {
+    if material.uv_transform.is_identity() {
+        return triangle.uvs;
+    }
+    let (dpdu, dpdv) = triangle.surface_derivatives();
+    triangle.uvs.map(|uv| {
+        let mut placed = Intersect::new(Vec3::zeros(), Vec3::zeros(), 0.0, material.clone(), uv.x, uv.y)
+            .with_surface_derivatives(dpdu, dpdv)
+            .apply_uv_transform();
+        placed.transform_surface(linear);
+        Vec2::new(placed.u, placed.v)
+    })
+}
+
+/// Procedural patterns have no image to refer to, so they're written as plain colors.
+fn image_texture(source: Option<&TextureSource>) -> Option<&Arc<Texture>> {
+    match source {
+        Some(TextureSource::Image(texture)) => Some(texture),
+        _ => None,
+    }
+}
+
+/// Face coordinates moved into the material's atlas tile, if it has one. Unlike
+/// sampling, which repeats the tile, the export clamps to it, half a texel in
+/// so viewers filtering the full-size image don't pick up the neighbours.
+fn atlas_uv(material: &Material, u: f32, v: f32) -> (f32, f32) {
+    match (material.atlas_tile, image_texture(material.texture.as_ref())) {
+        (Some(tile), Some(texture)) => tile.clamp(
+            tile.u_min + u.clamp(0.0, 1.0) * (tile.u_max - tile.u_min),
+            tile.v_min + v.clamp(0.0, 1.0) * (tile.v_max - tile.v_min),
+            texture.width,
+            texture.height,
+        ),
+        _ => (u, v),
+    }
+}
+
+fn transform_point(matrix: &Mat4, point: &Vec3) -> Vec3 {
+    (matrix * Vec4::new(point.x, point.y, point.z, 1.0)).xyz()
+}
+
+fn normal_matrix(matrix: &Mat4) -> Mat3 {
+    let linear: Mat3 = matrix.fixed_view::<3, 3>(0, 0).into();
+    linear.try_inverse().map(|inverse| inverse.transpose()).unwrap_or(linear)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("could not write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::UvTransform;
+    use crate::ray_intersect::RayIntersect;
+
+    fn parse(line: &str) -> Vec<f32> {
+        line.split_whitespace().skip(1).map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn cube_exports_to_obj_with_the_coordinates_rays_see() {
+        let material = Material::new(Color::new(200, 40, 40), 10.0, [0.9, 0.1, 0.0, 0.0], 1.0, Color::black(), 0.0);
+        let cube = Cube::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(2.0, 3.0, 4.0), material);
+        let mut root = SceneNode::new("block");
+        root.add_block(cube.clone());
+
+        let dir = std::env::temp_dir().join(format!("raytracing-export-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cube.obj");
+        SceneExport::new(&root).write_obj(path.to_str().unwrap()).unwrap();
+        let obj = fs::read_to_string(&path).unwrap();
+        let mtl_written = dir.join("cube.mtl").exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(mtl_written);
+
+        let lines = |prefix: &str| -> Vec<Vec<f32>> {
+            obj.lines().filter(|line| line.starts_with(prefix)).map(parse).collect()
+        };
+        let (positions, uvs, normals) = (lines("v "), lines("vt "), lines("vn "));
+        assert_eq!(obj.lines().filter(|line| line.starts_with("f ")).count(), 12);
+        assert_eq!(positions.len(), 36);
+
+        // Each triangle's vertices are written in order, so the centroid of its
+        // texture coordinates is what a ray through its centroid must see.
+        for triangle in 0..12 {
+            let vertices = triangle * 3..triangle * 3 + 3;
+            let centroid = vertices.clone().map(|i| Vec3::from_row_slice(&positions[i])).sum::<Vec3>() / 3.0;
+            let uv = vertices.clone().map(|i| Vec2::from_row_slice(&uvs[i])).sum::<Vec2>() / 3.0;
+            let normal = Vec3::from_row_slice(&normals[vertices.start]);
+
+            let hit = cube.ray_intersect(&(centroid + normal), &-normal, 0.0);
+            assert!(hit.is_intersecting);
+            assert!((hit.point - centroid).magnitude() < 1e-5);
+            assert!((hit.normal - normal).magnitude() < 1e-5);
+            assert!(
+                (hit.u - uv.x).abs() < 1e-5 && (hit.v - uv.y).abs() < 1e-5,
+                "triangle {}: exported ({}, {}), ray hit ({}, {})",
+                triangle,
+                uv.x,
+                uv.y,
+                hit.u,
+                hit.v
+            );
+        }
+    }
+
+    #[test]
+    fn mesh_exports_with_its_uv_transform_applied() {
+        let material = Material::new(Color::new(200, 40, 40), 10.0, [0.9, 0.1, 0.0, 0.0], 1.0, Color::black(), 0.0)
+            .with_uv_transform(UvTransform {
+                scale: Vec2::new(2.0, 3.0),
+                ..UvTransform::identity()
+            });
+        let triangle = MeshTriangle {
+            positions: [Vec3::zeros(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)],
+            normals: None,
+            uvs: [Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)],
+        };
+        let mut root = SceneNode::new("model");
+        root.add_shared(Arc::new(Mesh::new(vec![triangle], material)));
+
+        let scene = SceneExport::new(&root);
+        let (_, triangles) = &scene.groups[0];
+        assert_eq!(triangles[0].uvs, [Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0), Vec2::new(0.0, 3.0)]);
+    }
+
+}
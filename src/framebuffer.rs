@@ -1,3 +1,12 @@
+/// How the two views of a stereo pair are packed into one image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,
+    TopBottom,
+    /// Red channel from the left eye, green and blue from the right, for red/cyan glasses.
+    Anaglyph,
+}
+
 pub struct Framebuffer {
     pub width: usize,
     pub height: usize,
@@ -36,4 +45,46 @@ impl Framebuffer {
     pub fn set_current_color(&mut self, color: u32) {
         self.current_color = color;
     }
+
+    /// Packs a stereo pair of equally sized views, left eye first (left or top).
+    pub fn stereo(left: &Framebuffer, right: &Framebuffer, layout: StereoLayout) -> Framebuffer {
+        let (width, height) = (left.width, left.height);
+        match layout {
+            StereoLayout::SideBySide => {
+                let mut combined = Framebuffer::new(width * 2, height);
+                for y in 0..height {
+                    let row = y * width;
+                    let out = y * width * 2;
+                    combined.buffer[out..out + width].copy_from_slice(&left.buffer[row..row + width]);
+                    combined.buffer[out + width..out + width * 2]
+                        .copy_from_slice(&right.buffer[row..row + width]);
+                }
+                combined
+            }
+            StereoLayout::TopBottom => {
+                let mut combined = Framebuffer::new(width, height * 2);
+                combined.buffer[..width * height].copy_from_slice(&left.buffer);
+                combined.buffer[width * height..].copy_from_slice(&right.buffer);
+                combined
+            }
+            StereoLayout::Anaglyph => {
+                let mut combined = Framebuffer::new(width, height);
+                for (i, pixel) in combined.buffer.iter_mut().enumerate() {
+                    *pixel = (left.buffer[i] & 0xFF0000) | (right.buffer[i] & 0x00FFFF);
+                }
+                combined
+            }
+        }
+    }
+
+    /// Writes the buffer to an image file; the format follows the extension.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let image = image::RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let pixel = self.buffer[y as usize * self.width + x as usize];
+            image::Rgb([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        });
+        image
+            .save(path)
+            .map_err(|e| format!("could not save {}: {}", path, e))
+    }
 }
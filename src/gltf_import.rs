@@ -0,0 +1,353 @@
+use gltf::camera::Projection as GltfProjection;
+use gltf::khr_lights_punctual::Kind;
+use gltf::material::AlphaMode;
+use gltf::mesh::Mode;
+use gltf::texture::WrappingMode;
+use image::{DynamicImage, ImageBuffer};
+use nalgebra_glm::{Mat3, Mat4, Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::animation::CameraPose;
+use crate::camera::Projection;
+use crate::color::Color;
+use crate::light::{Falloff, Light};
+use crate::material::Material;
+use crate::mesh::{Mesh, Triangle};
+use crate::ray_intersect::RayIntersect;
+use crate::scene_graph::SceneNode;
+use crate::texture::{FilterMode, Texture, WrapMode};
+
+/// A camera defined in a glTF file, placed in the scene.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportedCamera {
+    pub pose: CameraPose,
+    pub projection: Projection,
+}
+
+/// The default scene of a glTF file as a scene graph, plus the cameras it defines.
+pub struct GltfScene {
+    pub node: SceneNode,
+    pub cameras: Vec<ImportedCamera>,
+}
+
+/// Reads a `.gltf` or `.glb` file. glTF nodes become scene nodes of the same name
+/// under one node called `name`, which `placement` puts in the scene; meshes used
+/// by several nodes are shared rather than copied. Only triangle primitives are read.
+pub fn load(path: &str, name: &str, placement: Mat4) -> Result<GltfScene, String> {
+    let (document, buffers, images) = gltf::import(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| format!("{}: the file has no scene", path))?;
+
+    let mut importer = Importer {
+        buffers: &buffers,
+        images: &images,
+        origin: (placement * Vec4::new(0.0, 0.0, 0.0, 1.0)).xyz(),
+        meshes: HashMap::new(),
+        textures: HashMap::new(),
+        cameras: Vec::new(),
+    };
+    let mut root = SceneNode::new(name).with_transform(placement);
+    for node in scene.nodes() {
+        root.add_child(importer.node(&node, &placement)?);
+    }
+
+    Ok(GltfScene {
+        node: root,
+        cameras: importer.cameras,
+    })
+}
+
+/// A decoded texture's image index, sampler index and the bits of the tint baked into it.
+type TextureKey = (usize, Option<usize>, [u32; 4]);
+
+struct Importer<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    /// Where the model's origin lands in the scene; imported cameras look toward it.
+    origin: Vec3,
+    meshes: HashMap<usize, Vec<Arc<dyn RayIntersect>>>,
+    textures: HashMap<TextureKey, Option<Arc<Texture>>>,
+    cameras: Vec<ImportedCamera>,
+}
+
+impl Importer<'_> {
+    fn node(&mut self, node: &gltf::Node, parent: &Mat4) -> Result<SceneNode, String> {
+        let name = node.name().map(str::to_string).unwrap_or_else(|| format!("node_{}", node.index()));
+        let transform = Mat4::from_column_slice(node.transform().matrix().as_flattened());
+        let world = parent * transform;
+        let mut scene_node = SceneNode::new(&name).with_transform(transform);
+        // A zero scale is how glTF hides a node; it has no inverse to trace through.
+        scene_node.visible = transform.try_inverse().is_some();
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in self.mesh(&mesh)? {
+                scene_node.add_shared(primitive);
+            }
+        }
+        if let Some(camera) = node.camera() {
+            self.cameras.push(self.camera(&camera, &world));
+        }
+        if let Some(light) = node.light() {
+            scene_node.add_light(punctual_light(&light, &world));
+        }
+        for child in node.children() {
+            scene_node.add_child(self.node(&child, &world)?);
+        }
+        Ok(scene_node)
+    }
+
+    fn mesh(&mut self, mesh: &gltf::Mesh) -> Result<Vec<Arc<dyn RayIntersect>>, String> {
+        if let Some(primitives) = self.meshes.get(&mesh.index()) {
+            return Ok(primitives.clone());
+        }
+
+        let mut primitives: Vec<Arc<dyn RayIntersect>> = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                eprintln!(
+                    "warning: skipping primitive {} of mesh {} drawn as {:?}; only triangles are supported",
+                    primitive.index(),
+                    mesh.index(),
+                    primitive.mode()
+                );
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let positions: Vec<Vec3> = reader
+                .read_positions()
+                .ok_or("mesh primitive without positions")?
+                .map(Vec3::from)
+                .collect();
+            let normals: Option<Vec<Vec3>> = reader.read_normals().map(|normals| normals.map(Vec3::from).collect());
+            let material = primitive.material();
+            let tex_coord = material
+                .pbr_metallic_roughness()
+                .base_color_texture()
+                .map_or(0, |info| info.tex_coord());
+            // glTF puts v = 0 at the top of the image; textures here have it at the bottom.
+            let uvs: Vec<Vec2> = match reader.read_tex_coords(tex_coord) {
+                Some(uvs) => uvs.into_f32().map(|[u, v]| Vec2::new(u, 1.0 - v)).collect(),
+                None => vec![Vec2::zeros(); positions.len()],
+            };
+            let indices: Vec<usize> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().map(|index| index as usize).collect(),
+                None => (0..positions.len()).collect(),
+            };
+            if normals.as_ref().is_some_and(|normals| normals.len() != positions.len()) {
+                return Err("mesh primitive has a different number of normals and positions".to_string());
+            }
+            if uvs.len() != positions.len() {
+                return Err("mesh primitive has a different number of texture coordinates and positions".to_string());
+            }
+
+            let mut triangles = Vec::with_capacity(indices.len() / 3);
+            for corners in indices.chunks_exact(3) {
+                let [a, b, c] = [corners[0], corners[1], corners[2]];
+                if [a, b, c].iter().any(|&index| index >= positions.len()) {
+                    return Err("mesh index out of range".to_string());
+                }
+                triangles.push(Triangle {
+                    positions: [positions[a], positions[b], positions[c]],
+                    normals: normals.as_ref().map(|normals| [normals[a], normals[b], normals[c]]),
+                    uvs: [uvs[a], uvs[b], uvs[c]],
+                });
+            }
+            primitives.push(Arc::new(Mesh::new(triangles, self.material(&material))));
+        }
+
+        self.meshes.insert(mesh.index(), primitives.clone());
+        Ok(primitives)
+    }
+
+    /// Approximates a metallic-roughness material with the renderer's Phong weights:
+    /// smoother surfaces get a tighter, brighter highlight and metals reflect their surroundings.
+    fn material(&mut self, material: &gltf::Material) -> Material {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let metallic = pbr.metallic_factor().clamp(0.0, 1.0);
+        let smoothness = 1.0 - pbr.roughness_factor().clamp(0.0, 1.0);
+
+        let mut transparency = 0.0;
+        if material.alpha_mode() == AlphaMode::Blend {
+            transparency = 1.0 - alpha.clamp(0.0, 1.0);
+        }
+        if let Some(transmission) = material.transmission() {
+            transparency = transmission.transmission_factor().clamp(0.0, 1.0).max(transparency);
+        }
+        let albedo = [
+            0.9 * (1.0 - metallic) * (1.0 - transparency),
+            0.3 * smoothness,
+            0.8 * metallic,
+            transparency,
+        ];
+        let specular = 1.0 + 127.0 * smoothness * smoothness;
+        let ior = material.ior().unwrap_or(1.5);
+
+        let [er, eg, eb] = material.emissive_factor();
+        let emission = er.max(eg).max(eb);
+        let emission_color = if emission > 0.0 {
+            to_srgb(Vec3::new(er, eg, eb) / emission)
+        } else {
+            Color::black()
+        };
+        // A full-strength emissive factor glows like glowstone.
+        let emission_intensity = 0.3 * emission;
+
+        // glTF multiplies the base color texture by the factor, so it's baked into the texels.
+        let base_texture = pbr
+            .base_color_texture()
+            .and_then(|info| self.texture(&info.texture(), pbr.base_color_factor()));
+        let mut imported = match base_texture {
+            Some(texture) => {
+                Material::new_with_texture(specular, albedo, ior, texture, None, emission_color, emission_intensity)
+            }
+            None => Material::new(
+                to_srgb(Vec3::new(r, g, b)),
+                specular,
+                albedo,
+                ior,
+                emission_color,
+                emission_intensity,
+            ),
+        };
+        if material.alpha_mode() == AlphaMode::Mask {
+            imported = imported.with_alpha_cutoff(material.alpha_cutoff().unwrap_or(0.5));
+        }
+        if let Some(normal_map) = material
+            .normal_texture()
+            .and_then(|normal| self.texture(&normal.texture(), [1.0; 4]))
+        {
+            imported = imported.with_normal_map(normal_map);
+        }
+        imported
+    }
+
+    /// The texture for a glTF texture with its linear RGBA `factor` multiplied in, decoded
+    /// once per image, sampler and factor. Images in formats other than 8 bits per
+    /// channel are skipped with a warning.
+    fn texture(&mut self, texture: &gltf::Texture, factor: [f32; 4]) -> Option<Arc<Texture>> {
+        let index = texture.source().index();
+        let key = (index, texture.sampler().index(), factor.map(f32::to_bits));
+        if let Some(cached) = self.textures.get(&key) {
+            return cached.clone();
+        }
+
+        let data = &self.images[index];
+        let (width, height, pixels) = (data.width, data.height, data.pixels.clone());
+        let image = match data.format {
+            gltf::image::Format::R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+            gltf::image::Format::R8G8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+            gltf::image::Format::R8G8B8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+            gltf::image::Format::R8G8B8A8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+            format => {
+                eprintln!("warning: skipping image {} in unsupported format {:?}", index, format);
+                None
+            }
+        };
+
+        let wrap = |mode: WrappingMode| match mode {
+            WrappingMode::ClampToEdge => WrapMode::Clamp,
+            WrappingMode::MirroredRepeat => WrapMode::Mirror,
+            WrappingMode::Repeat => WrapMode::Repeat,
+        };
+        let sampler = texture.sampler();
+        let texture = image.map(|image| {
+            Arc::new(
+                Texture::from_image(tinted(image, factor))
+                    .with_filter(FilterMode::Trilinear)
+                    .with_wrap_uv(wrap(sampler.wrap_s()), wrap(sampler.wrap_t())),
+            )
+        });
+        self.textures.insert(key, texture.clone());
+        texture
+    }
+
+    /// glTF cameras look down their local -Z; the target is put level with the model's origin.
+    fn camera(&self, camera: &gltf::Camera, world: &Mat4) -> ImportedCamera {
+        let position = (world * Vec4::new(0.0, 0.0, 0.0, 1.0)).xyz();
+        let forward = (world * Vec4::new(0.0, 0.0, -1.0, 0.0)).xyz().normalize();
+        let distance = (self.origin - position).dot(&forward).max(1.0);
+        let mut pose = CameraPose {
+            position,
+            target: position + forward * distance,
+            fov: None,
+            focus_distance: Some(distance),
+        };
+
+        let projection = match camera.projection() {
+            GltfProjection::Perspective(perspective) => {
+                pose.fov = Some(perspective.yfov());
+                Projection::Perspective
+            }
+            GltfProjection::Orthographic(orthographic) => Projection::Orthographic {
+                height: 2.0 * orthographic.ymag() * scale_of(world),
+            },
+        };
+        ImportedCamera { pose, projection }
+    }
+}
+
+/// Illuminance, in lux, of light at intensity 1.0 in the scene: the fixed lights
+/// run at 1.0 to 1.5 with no falloff. 683 lux is one watt per square meter at 555 nm.
+const LUX_PER_INTENSITY: f32 = 683.0;
+
+/// A `KHR_lights_punctual` light in its node's local space, pointing down -Z.
+/// Directional lights give their illuminance in lux and are divided by
+/// `LUX_PER_INTENSITY`. Point and spot lights give candela, which at `d` glTF
+/// units is `candela / d^2` lux. They get the same division, and are scaled by the
+/// node's size squared, so a surface the same number of glTF units away gets the
+/// same brightness as under a directional light of that many lux.
+fn punctual_light(light: &gltf::khr_lights_punctual::Light, world: &Mat4) -> Light {
+    let color = to_srgb(Vec3::from(light.color()));
+    let scale = scale_of(world);
+    let intensity = light.intensity() / LUX_PER_INTENSITY * scale * scale;
+    let position = Vec3::zeros();
+    let direction = Vec3::new(0.0, 0.0, -1.0);
+
+    match light.kind() {
+        Kind::Directional => Light::directional(direction, color, light.intensity() / LUX_PER_INTENSITY),
+        Kind::Point => Light::point(position, color, intensity, Falloff::InverseSquare),
+        Kind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => Light::spot(
+            position,
+            direction,
+            color,
+            intensity,
+            inner_cone_angle,
+            outer_cone_angle,
+            Falloff::InverseSquare,
+        ),
+    }
+}
+
+/// Average scale factor of a transform.
+fn scale_of(matrix: &Mat4) -> f32 {
+    let linear: Mat3 = matrix.fixed_view::<3, 3>(0, 0).into();
+    linear.determinant().abs().cbrt()
+}
+
+/// `image` with each texel multiplied by the linear RGBA `factor`; unchanged for a factor of one.
+fn tinted(image: DynamicImage, factor: [f32; 4]) -> DynamicImage {
+    if factor == [1.0; 4] {
+        return image;
+    }
+    let mut rgba = image.into_rgba8();
+    for pixel in rgba.pixels_mut() {
+        for (channel, scale) in pixel.0.iter_mut().zip(factor).take(3) {
+            let linear = (*channel as f32 / 255.0).powf(2.2) * scale.max(0.0);
+            *channel = (linear.powf(1.0 / 2.2).min(1.0) * 255.0).round() as u8;
+        }
+        pixel.0[3] = (pixel.0[3] as f32 * factor[3].clamp(0.0, 1.0)).round() as u8;
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// glTF colors are linear; the renderer's are sRGB-encoded.
+fn to_srgb(linear: Vec3) -> Color {
+    Color::from_vec3(linear.map(|channel| channel.max(0.0).powf(1.0 / 2.2)))
+}
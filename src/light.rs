@@ -1,19 +1,324 @@
-use nalgebra_glm::Vec3;
+use nalgebra_glm::{Mat3, Vec3};
+use rand::random;
+use crate::animation::IntensityCurve;
 use crate::color::Color;
+use crate::motion::Motion;
+
+/// How a light's intensity decreases with distance.
+#[derive(Debug, Clone, Copy)]
+pub enum Falloff {
+    None,
+    InverseSquare,
+    Custom {
+        constant: f32,
+        linear: f32,
+        quadratic: f32,
+    },
+}
+
+impl Falloff {
+    pub fn attenuate(&self, distance: f32) -> f32 {
+        match *self {
+            Falloff::None => 1.0,
+            Falloff::InverseSquare => 1.0 / (distance * distance).max(1e-4),
+            Falloff::Custom {
+                constant,
+                linear,
+                quadratic,
+            } => 1.0 / (constant + linear * distance + quadratic * distance * distance).max(1e-4),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LightKind {
+    Point {
+        falloff: Falloff,
+    },
+    /// Infinitely far away; `direction` is the way the light travels.
+    Directional {
+        direction: Vec3,
+    },
+    /// Cone angles are half-angles in radians measured from `direction`.
+    Spot {
+        direction: Vec3,
+        inner_angle: f32,
+        outer_angle: f32,
+        falloff: Falloff,
+    },
+    /// A spherical emitter; `samples` shadow rays are spread over its surface for soft shadows.
+    Sphere {
+        radius: f32,
+        samples: u32,
+        falloff: Falloff,
+    },
+    /// An emissive block; `half_axes` holds the vectors from its center to the middle
+    /// of its +X, +Y and +Z faces. Shadow rays aim at points on the emitting `faces`
+    /// (ordered +X, -X, +Y, -Y, +Z, -Z) the lit point can see, so the block itself
+    /// doesn't get in the way.
+    Cuboid {
+        half_axes: Mat3,
+        faces: [bool; 6],
+        samples: u32,
+        falloff: Falloff,
+    },
+}
 
 #[derive(Clone)] // Esto permite clonar instancias de Light
 pub struct Light {
     pub position: Vec3,
     pub color: Color,
     pub intensity: f32,
+    pub kind: LightKind,
+    /// Scales `intensity` over scene time.
+    pub intensity_curve: Option<IntensityCurve>,
+    /// Moves and turns the light over time, e.g. along with the block emitting it.
+    pub motion: Option<Motion>,
 }
 
 impl Light {
     pub fn new(position: Vec3, color: Color, intensity: f32) -> Self {
+        Light::point(position, color, intensity, Falloff::None)
+    }
+
+    pub fn point(position: Vec3, color: Color, intensity: f32, falloff: Falloff) -> Self {
+        Light {
+            position,
+            color,
+            intensity,
+            intensity_curve: None,
+            motion: None,
+            kind: LightKind::Point { falloff },
+        }
+    }
+
+    pub fn directional(direction: Vec3, color: Color, intensity: f32) -> Self {
+        Light {
+            position: Vec3::new(0.0, 0.0, 0.0),
+            color,
+            intensity,
+            intensity_curve: None,
+            motion: None,
+            kind: LightKind::Directional {
+                direction: direction.normalize(),
+            },
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        color: Color,
+        intensity: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+        falloff: Falloff,
+    ) -> Self {
+        Light {
+            position,
+            color,
+            intensity,
+            intensity_curve: None,
+            motion: None,
+            kind: LightKind::Spot {
+                direction: direction.normalize(),
+                inner_angle,
+                outer_angle: outer_angle.max(inner_angle),
+                falloff,
+            },
+        }
+    }
+
+    pub fn sphere(
+        position: Vec3,
+        radius: f32,
+        color: Color,
+        intensity: f32,
+        samples: u32,
+        falloff: Falloff,
+    ) -> Self {
         Light {
             position,
             color,
             intensity,
+            intensity_curve: None,
+            motion: None,
+            kind: LightKind::Sphere {
+                radius,
+                samples: samples.max(1),
+                falloff,
+            },
         }
     }
+
+    pub fn cuboid(
+        center: Vec3,
+        half_axes: Mat3,
+        faces: [bool; 6],
+        color: Color,
+        intensity: f32,
+        samples: u32,
+        falloff: Falloff,
+    ) -> Self {
+        Light {
+            position: center,
+            color,
+            intensity,
+            intensity_curve: None,
+            motion: None,
+            kind: LightKind::Cuboid {
+                half_axes,
+                faces,
+                samples: samples.max(1),
+                falloff,
+            },
+        }
+    }
+
+    pub fn with_intensity_curve(mut self, intensity_curve: IntensityCurve) -> Self {
+        self.intensity_curve = Some(intensity_curve);
+        self
+    }
+
+    pub fn intensity_at(&self, time: f32) -> f32 {
+        match &self.intensity_curve {
+            Some(curve) => self.intensity * curve.factor(time),
+            None => self.intensity,
+        }
+    }
+
+    pub fn with_motion(mut self, motion: Motion) -> Self {
+        self.motion = Some(motion);
+        self
+    }
+
+    pub fn position_at(&self, time: f32) -> Vec3 {
+        match &self.motion {
+            Some(motion) => motion.point_at(&self.position, time),
+            None => self.position,
+        }
+    }
+
+    /// A direction or extent of the light, turned and scaled by its motion at `time`.
+    fn vector_at(&self, vector: Vec3, time: f32) -> Vec3 {
+        match &self.motion {
+            Some(motion) => motion.vector_at(&vector, time),
+            None => vector,
+        }
+    }
+
+    /// Unit vector from `point` towards the light at `time`.
+    pub fn direction_from(&self, point: &Vec3, time: f32) -> Vec3 {
+        match self.kind {
+            LightKind::Directional { direction } => -self.vector_at(direction, time).normalize(),
+            _ => (self.position_at(time) - point).normalize(),
+        }
+    }
+
+    /// Distance and spot-cone attenuation at `point`, in `[0, 1]` for the built-in falloffs.
+    pub fn attenuation(&self, point: &Vec3, time: f32) -> f32 {
+        let position = self.position_at(time);
+        let distance = (position - point).magnitude();
+
+        match self.kind {
+            LightKind::Point { falloff }
+            | LightKind::Sphere { falloff, .. }
+            | LightKind::Cuboid { falloff, .. } => {
+                falloff.attenuate(distance)
+            }
+            LightKind::Directional { .. } => 1.0,
+            LightKind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+                falloff,
+            } => {
+                let direction = self.vector_at(direction, time).normalize();
+                let cos_angle = (point - position).normalize().dot(&direction);
+                let cos_inner = inner_angle.cos();
+                let cos_outer = outer_angle.cos();
+                let cone = if cos_inner - cos_outer <= f32::EPSILON {
+                    if cos_angle >= cos_outer { 1.0 } else { 0.0 }
+                } else {
+                    ((cos_angle - cos_outer) / (cos_inner - cos_outer)).clamp(0.0, 1.0)
+                };
+                cone * falloff.attenuate(distance)
+            }
+        }
+    }
+
+    /// Shadow rays needed to test the visibility of this light from `point`,
+    /// as `(direction, max_distance)` pairs. Empty when no part of the light faces `point`.
+    pub fn shadow_rays(&self, point: &Vec3, time: f32) -> Vec<(Vec3, f32)> {
+        let position = self.position_at(time);
+        match self.kind {
+            LightKind::Point { .. } | LightKind::Spot { .. } => {
+                let to_light = position - point;
+                vec![(to_light.normalize(), to_light.magnitude())]
+            }
+            LightKind::Directional { direction } => {
+                vec![(-self.vector_at(direction, time).normalize(), f32::INFINITY)]
+            }
+            LightKind::Sphere { radius, samples, .. } => (0..samples)
+                .map(|_| {
+                    let target = position + random_unit_vector() * radius;
+                    let to_light = target - point;
+                    (to_light.normalize(), to_light.magnitude())
+                })
+                .collect(),
+            LightKind::Cuboid { half_axes, faces, samples, .. } => {
+                let half_axes = Mat3::from_columns(&[0, 1, 2].map(|axis| self.vector_at(half_axes.column(axis).into(), time)));
+                // An emitting face is visible from the points on the outside of its plane.
+                let faces: Vec<(Vec3, Vec3, Vec3)> = (0..3)
+                    .flat_map(|axis| [(axis, 1.0), (axis, -1.0)])
+                    .zip(faces)
+                    .filter(|(_, emits)| *emits)
+                    .filter_map(|((axis, sign), _)| {
+                        let outward: Vec3 = half_axes.column(axis) * sign;
+                        let center = position + outward;
+                        let across: Vec3 = half_axes.column((axis + 1) % 3).into();
+                        let along: Vec3 = half_axes.column((axis + 2) % 3).into();
+                        ((point - center).dot(&outward) > 0.0).then_some((center, across, along))
+                    })
+                    .collect();
+                let areas: Vec<f32> = faces.iter().map(|(_, a, b)| a.cross(b).magnitude()).collect();
+                let total: f32 = areas.iter().sum();
+                if faces.is_empty() || total <= 0.0 {
+                    return Vec::new();
+                }
+
+                (0..samples)
+                    .map(|_| {
+                        let mut pick = random::<f32>() * total;
+                        let mut face = faces.len() - 1;
+                        for (index, area) in areas.iter().enumerate() {
+                            if pick < *area {
+                                face = index;
+                                break;
+                            }
+                            pick -= area;
+                        }
+                        let (center, across, along) = faces[face];
+                        let target = center
+                            + across * (random::<f32>() * 2.0 - 1.0)
+                            + along * (random::<f32>() * 2.0 - 1.0);
+                        let to_light = target - point;
+                        // Stop just short of the face, which would otherwise block its own light.
+                        (to_light.normalize(), to_light.magnitude() - SURFACE_GAP)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// How far before a cuboid light's surface its shadow rays stop.
+const SURFACE_GAP: f32 = 1e-3;
+
+fn random_unit_vector() -> Vec3 {
+    let z = random::<f32>() * 2.0 - 1.0;
+    let phi = random::<f32>() * 2.0 * std::f32::consts::PI;
+    let r = (1.0 - z * z).sqrt();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
 }
@@ -1,30 +1,58 @@
-use crate::texture::Texture;
-use minifb::{Key, Window, WindowOptions};
-use nalgebra_glm::{normalize, Vec3};
-use rand::random;
+use crate::texture::{FilterMode, Texture};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window, WindowOptions};
+use nalgebra_glm::{Mat3, Mat4, Vec3, Vec4};
+use rand::rngs::StdRng;
+use rand::{random, SeedableRng};
 use rayon::prelude::*;
 use std::f32::consts::PI;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+mod animation;
+mod atlas;
 mod camera;
+mod cli;
 mod color;
 mod cube;
+mod export;
 mod framebuffer;
+mod gltf_import;
 mod light;
 mod material;
+mod mesh;
+mod motion;
+mod nbt;
+mod procedural;
 mod ray_intersect;
+mod scene;
+mod scene_graph;
+mod schematic;
+mod structure;
+mod terrain;
 mod texture;
-
-use crate::cube::Cube;
-use camera::Camera;
+mod transform;
+mod vox;
+
+use crate::cube::{Cube, CubeFace};
+use crate::motion::{Motion, Moving};
+use crate::transform::{Instance, Transformed};
+use animation::CameraPath;
+use camera::{Bokeh, Camera, CameraMode, Eye, FisheyeMapping, Lens, Projection, Shutter, Stereo};
 use color::Color;
-use framebuffer::Framebuffer;
-use light::Light;
-use material::Material;
-use ray_intersect::{Intersect, RayIntersect};
+use framebuffer::{Framebuffer, StereoLayout};
+use light::{Falloff, Light};
+use material::{Material, UvTransform};
+use procedural::{Pattern, ProceduralTexture, TextureSpace};
+use ray_intersect::{Intersect, RayCone, RayIntersect};
+use scene::SceneFile;
+use scene_graph::SceneNode;
+use std::collections::HashMap;
+use terrain::Ground;
+use std::ops::Range;
+use std::path::Path;
 
 const ORIGIN_BIAS: f32 = 1e-4;
+const MIN_TRANSMITTANCE: f32 = 1e-3;
 
 fn offset_origin(intersect: &Intersect, direction: &Vec3) -> Vec3 {
     let offset = intersect.normal * ORIGIN_BIAS;
@@ -40,7 +68,7 @@ fn reflect(incident: &Vec3, normal: &Vec3) -> Vec3 {
 }
 
 fn refract(incident: &Vec3, normal: &Vec3, eta_t: f32) -> Vec3 {
-    let cosi = -incident.dot(normal).max(-1.0).min(1.0);
+    let cosi = -incident.dot(normal).clamp(-1.0, 1.0);
 
     let (n_cosi, eta, n_normal);
 
@@ -63,32 +91,66 @@ fn refract(incident: &Vec3, normal: &Vec3, eta_t: f32) -> Vec3 {
     }
 }
 
-fn cast_shadow(intersect: &Intersect, light: &Light, objects: &[&dyn RayIntersect]) -> f32 {
-    let light_dir = (light.position - intersect.point).normalize();
-    let light_distance = (light.position - intersect.point).magnitude();
-
-    let shadow_ray_origin = offset_origin(intersect, &light_dir);
-    let mut shadow_intensity = 0.0;
+/// Any-hit shadow query: returns the per-channel fraction of light that travels
+/// `light_distance` along `light_dir` from the hit point. Opaque occluders end the
+/// query immediately; transparent ones tint and dim the light by their diffuse color.
+fn cast_shadow(
+    intersect: &Intersect,
+    light_dir: &Vec3,
+    light_distance: f32,
+    objects: &[&dyn RayIntersect],
+) -> Vec3 {
+    let shadow_ray_origin = offset_origin(intersect, light_dir);
+    let mut transmittance = Vec3::new(1.0, 1.0, 1.0);
 
     for object in objects {
-        let shadow_intersect = object.ray_intersect(&shadow_ray_origin, &light_dir);
-        if shadow_intersect.is_intersecting && shadow_intersect.distance < light_distance {
-            let distance_ratio = shadow_intersect.distance / light_distance;
-            shadow_intensity = 1.0 - distance_ratio.powf(2.0).min(1.0);
-            break;
+        let Some(shadow_intersect) =
+            object.shadow_intersect(&shadow_ray_origin, light_dir, light_distance, intersect.time)
+        else {
+            continue;
+        };
+
+        let material = &shadow_intersect.material;
+        let transparency = material.albedo[3];
+        if transparency <= 0.0 {
+            return Vec3::zeros();
+        }
+
+        let tint = material.get_diffuse_color(&shadow_intersect).to_vec3();
+        transmittance = transmittance.component_mul(&(tint * transparency));
+
+        if transmittance.max() < MIN_TRANSMITTANCE {
+            return Vec3::zeros();
         }
     }
 
-    shadow_intensity * 0.9
+    transmittance
 }
 
+fn light_transmittance(intersect: &Intersect, light: &Light, objects: &[&dyn RayIntersect]) -> Vec3 {
+    let shadow_rays = light.shadow_rays(&intersect.point, intersect.time);
+    if shadow_rays.is_empty() {
+        return Vec3::zeros();
+    }
+    let total = shadow_rays
+        .iter()
+        .fold(Vec3::zeros(), |total, (light_dir, light_distance)| {
+            total + cast_shadow(intersect, light_dir, *light_distance, objects)
+        });
+
+    total / shadow_rays.len() as f32
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn cast_ray(
     ray_origin: &Vec3,
     ray_direction: &Vec3,
+    ray_cone: &RayCone,
     objects: &[&dyn RayIntersect],
     lights: &[Light],
     depth: u32,
     skybox_texture: &Texture,
+    time: f32,
 ) -> Color {
     if depth > 3 {
         let dir = ray_direction.normalize();
@@ -103,7 +165,7 @@ pub fn cast_ray(
     let mut zbuffer = f32::INFINITY;
 
     for object in objects {
-        let i = object.ray_intersect(ray_origin, ray_direction);
+        let i = object.ray_intersect(ray_origin, ray_direction, time);
         if i.is_intersecting && i.distance < zbuffer {
             zbuffer = i.distance;
             intersect = i;
@@ -119,30 +181,50 @@ pub fn cast_ray(
         return skybox_texture.get_color(u, v);
     }
 
+    intersect.compute_uv_derivatives(ray_direction, ray_cone.width_at(intersect.distance));
+    let hit_cone = ray_cone.propagate(intersect.distance);
+
     let view_dir = (ray_origin - intersect.point).normalize();
+    let (u, v) = intersect.material.get_parallax_uv(&intersect, &view_dir);
+    intersect.u = u;
+    intersect.v = v;
+    let normal = intersect.material.get_shading_normal(&intersect);
+    let specular_exponent = intersect.material.get_specular_exponent(&intersect);
 
     let mut final_color = Color::black();
 
     for light in lights {
-        let light_dir = (light.position - intersect.point).normalize();
-        let reflect_dir = reflect(&-light_dir, &intersect.normal).normalize();
+        let attenuation = light.attenuation(&intersect.point, time);
+        if attenuation <= 0.0 {
+            continue;
+        }
 
-        let shadow_intensity = cast_shadow(&intersect, light, objects);
-        let light_intensity = light.intensity * (1.0 - shadow_intensity);
+        let light_dir = light.direction_from(&intersect.point, time);
+        let reflect_dir = reflect(&-light_dir, &normal).normalize();
+
+        let transmittance = light_transmittance(&intersect, light, objects);
+        if transmittance.max() <= 0.0 {
+            continue;
+        }
+        let light_intensity = light.intensity_at(time) * attenuation;
 
-        let diffuse_intensity = intersect.normal.dot(&light_dir).max(0.0).min(1.0);
-        let diffuse_color = intersect
-            .material
-            .get_diffuse_color(intersect.u, intersect.v);
-        let diffuse =
-            diffuse_color * intersect.material.albedo[0] * diffuse_intensity * light_intensity;
+        let diffuse_intensity = normal.dot(&light_dir).clamp(0.0, 1.0);
+        let diffuse_color = intersect.material.get_diffuse_color(&intersect);
+        let diffuse = diffuse_color
+            * transmittance
+            * intersect.material.albedo[0]
+            * diffuse_intensity
+            * light_intensity;
 
         let specular_intensity = view_dir
             .dot(&reflect_dir)
             .max(0.0)
-            .powf(intersect.material.specular);
-        let specular =
-            light.color * intersect.material.albedo[1] * specular_intensity * light_intensity;
+            .powf(specular_exponent);
+        let specular = light.color
+            * transmittance
+            * intersect.material.albedo[1]
+            * specular_intensity
+            * light_intensity;
 
         final_color += diffuse + specular;
     }
@@ -150,15 +232,17 @@ pub fn cast_ray(
     let mut reflect_color = Color::black();
     let reflectivity = intersect.material.albedo[2];
     if reflectivity > 0.0 {
-        let reflect_dir = reflect(&ray_direction, &intersect.normal).normalize();
+        let reflect_dir = reflect(ray_direction, &normal).normalize();
         let reflect_origin = offset_origin(&intersect, &reflect_dir);
         reflect_color = cast_ray(
             &reflect_origin,
             &reflect_dir,
+            &hit_cone,
             objects,
             lights,
             depth + 1,
             skybox_texture,
+            time,
         );
     }
 
@@ -166,23 +250,25 @@ pub fn cast_ray(
     let transparency = intersect.material.albedo[3];
     if transparency > 0.0 {
         let refract_dir = refract(
-            &ray_direction,
-            &intersect.normal,
+            ray_direction,
+            &normal,
             intersect.material.refractive_index,
         );
         let refract_origin = offset_origin(&intersect, &refract_dir);
         refract_color = cast_ray(
             &refract_origin,
             &refract_dir,
+            &hit_cone,
             objects,
             lights,
             depth + 1,
             skybox_texture,
+            time,
         );
     }
 
     if intersect.material.emission_intensity > 0.0 {
-        final_color += intersect.material.emission_color * intersect.material.emission_intensity;
+        final_color += intersect.material.emission_color * intersect.material.get_emission_intensity(time);
     }
 
     final_color = final_color * (1.0 - reflectivity - transparency)
@@ -198,12 +284,11 @@ pub fn render(
     camera: &Camera,
     lights: &[Light],
     skybox_texture: &Texture,
+    time: f32,
 ) {
-    let width = framebuffer.width as usize;
-    let height = framebuffer.height as usize;
-    let aspect_ratio = width as f32 / height as f32;
-    let fov = PI / 3.0;
-    let perspective_scale = (fov * 0.5).tan();
+    let width = framebuffer.width;
+    let height = framebuffer.height;
+    let pixel_cone = camera.pixel_cone(width, height);
 
     let buffer = Arc::new(Mutex::new(&mut framebuffer.buffer));
 
@@ -213,31 +298,236 @@ pub fn render(
         let x = i % width;
         let y = i / width;
 
-        let screen_x = (2.0 * x as f32) / width as f32 - 1.0;
-        let screen_y = -(2.0 * y as f32) / height as f32 + 1.0;
-
-        let screen_x = screen_x * aspect_ratio * perspective_scale;
-        let screen_y = screen_y * perspective_scale;
-
-        let ray_direction = normalize(&Vec3::new(screen_x, screen_y, -1.0));
-        let rotated_direction = camera.basis_change(&ray_direction);
-
-        let pixel_color = cast_ray(
-            &camera.eye,
-            &rotated_direction,
-            objects,
-            lights,
-            0,
-            skybox_texture,
-        );
+        let samples = camera.samples_per_pixel();
+        let mut sum = Vec3::zeros();
+        for sample in 0..samples {
+            // Stratified over the exposure so few samples still cover the whole shutter interval.
+            let shutter_fraction = if camera.shutter.is_open() {
+                (sample as f32 + random::<f32>()) / samples as f32
+            } else {
+                0.0
+            };
+            let ray_time = time + shutter_fraction * camera.shutter.duration;
+            let Some((ray_origin, ray_direction)) =
+                camera.generate_ray(x as f32, y as f32, width, height, shutter_fraction)
+            else {
+                continue;
+            };
+            let color = cast_ray(
+                &ray_origin,
+                &ray_direction,
+                &pixel_cone,
+                objects,
+                lights,
+                0,
+                skybox_texture,
+                ray_time,
+            );
+            sum += color.to_vec3();
+        }
+        let pixel_color = Color::from_vec3(sum / samples as f32);
 
         let mut buffer = buffer.lock().unwrap();
         buffer[i] = pixel_color.to_hex();
     });
 }
 
+/// The block behind a scene object with its placement in the world and motion,
+/// looking through the wrappers the scene uses, including instances of a shared
+/// block. Used to turn emissive blocks into lights; meshes aren't blocks and
+/// don't become lights.
+fn placed_cube(object: &dyn std::any::Any) -> Option<(&Cube, Mat4, Option<Motion>)> {
+    fn instance(instance: &Instance) -> Option<(&Cube, Mat4)> {
+        let cube = instance.object.as_any().downcast_ref::<Cube>()?;
+        Some((cube, *instance.matrix()))
+    }
+
+    if let Some(moving) = object.downcast_ref::<Moving<Cube>>() {
+        Some((&moving.object, Mat4::identity(), Some(moving.motion)))
+    } else if let Some(moving) = object.downcast_ref::<Moving<Transformed<Cube>>>() {
+        Some((&moving.object.object, *moving.object.matrix(), Some(moving.motion)))
+    } else if let Some(moving) = object.downcast_ref::<Moving<Instance>>() {
+        let (cube, placement) = instance(&moving.object)?;
+        Some((cube, placement, Some(moving.motion)))
+    } else if let Some(cube) = object.downcast_ref::<Cube>() {
+        Some((cube, Mat4::identity(), None))
+    } else if let Some(placed) = object.downcast_ref::<Transformed<Cube>>() {
+        Some((&placed.object, *placed.matrix(), None))
+    } else {
+        let (cube, placement) = instance(object.downcast_ref::<Instance>()?)?;
+        Some((cube, placement, None))
+    }
+}
+
+/// The fixed `lights`, the lights placed in the scene graph under `root`, and a
+/// light for every emissive block among `objects`.
+fn scene_lights(root: &SceneNode, objects: &[Box<dyn RayIntersect>], lights: &[Light]) -> Vec<Light> {
+    let mut all_lights = lights.to_vec();
+    all_lights.extend(root.flatten_lights());
+    for obj in objects {
+        let Some((cube, placement, motion)) = placed_cube(obj.as_any()) else {
+            continue;
+        };
+
+        // One light per distinct glow among the faces, sampling only the faces that have it.
+        let mut glows: Vec<(&Material, [bool; 6])> = Vec::new();
+        for face in CubeFace::ALL {
+            let material = cube.face_material(face);
+            if material.emission_intensity <= 0.0 {
+                continue;
+            }
+            let same_glow = |(other, _): &&mut (&Material, [bool; 6])| {
+                other.emission_color == material.emission_color
+                    && other.emission_intensity == material.emission_intensity
+                    && other.emission_curve == material.emission_curve
+            };
+            match glows.iter_mut().find(same_glow) {
+                Some((_, faces)) => faces[face.index()] = true,
+                None => {
+                    let mut faces = [false; 6];
+                    faces[face.index()] = true;
+                    glows.push((material, faces));
+                }
+            }
+        }
+
+        let center = (cube.min + cube.max) * 0.5;
+        let center = (placement * Vec4::new(center.x, center.y, center.z, 1.0)).xyz();
+        let half_size = (cube.max - cube.min) * 0.5;
+        let half_axes = placement.fixed_view::<3, 3>(0, 0) * Mat3::from_diagonal(&half_size);
+        for (material, faces) in glows {
+            let mut light = Light::cuboid(
+                center,
+                half_axes,
+                faces,
+                material.emission_color,
+                material.emission_intensity,
+                4,
+                Falloff::Custom {
+                    constant: 1.0,
+                    linear: 0.0,
+                    quadratic: 0.02,
+                },
+            );
+            if let Some(curve) = &material.emission_curve {
+                light = light.with_intensity_curve(curve.clone());
+            }
+            if let Some(motion) = motion {
+                light = light.with_motion(motion);
+            }
+            all_lights.push(light);
+        }
+    }
+    all_lights
+}
+
+/// Animated textures, flickering lights and moving blocks need a fresh frame every tick, not just on camera moves.
+fn any_animated(objects: &[Box<dyn RayIntersect>]) -> bool {
+    objects.iter().any(|obj| {
+        placed_cube(obj.as_any()).is_some_and(|(cube, _, motion)| motion.is_some() || cube.is_animated())
+    })
+}
+
+/// Renders `frames` of the camera path to `<output>/frame_NNNN.png`. Frames whose
+/// file already exists are skipped, so an interrupted run picks up where it stopped.
+#[allow(clippy::too_many_arguments)]
+fn render_sequence(
+    frames: Range<u32>,
+    output: &str,
+    scene: &SceneFile,
+    camera: &mut Camera,
+    camera_path: Option<&CameraPath>,
+    objects: &[&dyn RayIntersect],
+    lights: &[Light],
+    skybox_texture: &Texture,
+) -> Result<(), String> {
+    std::fs::create_dir_all(output)
+        .map_err(|e| format!("could not create output directory {}: {}", output, e))?;
+
+    let mut framebuffer = Framebuffer::new(scene.render.width, scene.render.height);
+    camera.shutter = Shutter::new(scene.render.shutter, scene.render.motion_samples);
+    let total = frames.len();
+    for (done, frame) in frames.enumerate() {
+        let path = Path::new(output).join(format!("frame_{:04}.png", frame));
+        if path.exists() {
+            println!("[{}/{}] {} exists, skipping", done + 1, total, path.display());
+            continue;
+        }
+
+        let time = frame as f32 / scene.render.fps;
+        if let Some(camera_path) = camera_path {
+            let open = camera_path.sample(time);
+            let close = camera_path.sample(time + camera.shutter.duration);
+            if let (Some(open), Some(close)) = (open, close) {
+                camera.set_shutter_motion(open, close);
+            }
+        }
+        render(&mut framebuffer, objects, camera, lights, skybox_texture, time);
+
+        // Write under a temporary name first so a killed run never leaves a truncated frame behind.
+        let partial = path.with_extension("partial.png");
+        framebuffer.save(&partial.to_string_lossy())?;
+        std::fs::rename(&partial, &path)
+            .map_err(|e| format!("could not move {} into place: {}", partial.display(), e))?;
+        println!("[{}/{}] {}", done + 1, total, path.display());
+    }
+    Ok(())
+}
+
+/// The seed for a sequence rendered to `output`. The first run records it in
+/// `<output>/seed.txt`; later runs reuse it, and refuse a different one.
+fn sequence_seed(output: &str, requested: Option<u64>) -> Result<u64, String> {
+    let path = Path::new(output).join("seed.txt");
+    if path.exists() {
+        let recorded: u64 = std::fs::read_to_string(&path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?
+            .trim()
+            .parse()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        return match requested {
+            Some(seed) if seed != recorded => Err(format!(
+                "the frames in {} were rendered with seed {}, not {}; use another output directory",
+                output, recorded, seed
+            )),
+            _ => Ok(recorded),
+        };
+    }
+
+    let seed = requested.unwrap_or_else(random);
+    std::fs::create_dir_all(output)
+        .map_err(|e| format!("could not create output directory {}: {}", output, e))?;
+    std::fs::write(&path, format!("{}\n", seed)).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(seed)
+}
+
 fn main() {
-    let snow_texture = Arc::new(Texture::new("assets/snow.png"));
+    let options = cli::Options::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let scene = match &options.scene {
+        Some(path) => SceneFile::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => SceneFile::default(),
+    };
+    let camera_path = scene.camera_path();
+
+    // Everything random about the scene comes from this seed, so a layout can be reproduced.
+    let requested_seed = options.seed.or(scene.seed);
+    let seed = match options.frames {
+        // Resumed frames must show the same layout as the ones already rendered.
+        Some(_) => sequence_seed(&options.output, requested_seed).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => requested_seed.unwrap_or_else(random),
+    };
+    println!("Seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let snow_texture = Arc::new(Texture::new("assets/snow.png").with_filter(FilterMode::Trilinear));
     let snow_material = Material::new_with_texture(
         2.0,
         [0.9, 0.1, 0.0, 0.0],
@@ -246,7 +536,22 @@ fn main() {
         None,
         Color::black(),
         0.0,
-    );
+    )
+    .with_uv_transform(UvTransform::world_space(0.5))
+    .with_normal_map(Arc::new(
+        ProceduralTexture::new(
+            Pattern::Noise {
+                octaves: 4,
+                lacunarity: 2.0,
+                gain: 0.5,
+            },
+            TextureSpace::World,
+            1.5,
+            7,
+        )
+        .with_bump_strength(0.3),
+    ))
+    .with_height_map(snow_texture.clone(), 0.15, 16);
 
     let ice_texture = Arc::new(Texture::new("assets/ice.png"));
     let ice_material = Material::new_with_texture(
@@ -259,7 +564,7 @@ fn main() {
         0.0,
     );
 
-    let stone_texture = Arc::new(Texture::new("assets/stone.png"));
+    let stone_texture = Arc::new(Texture::new("assets/stone.png").with_filter(FilterMode::Trilinear));
     let stone_material = Material::new_with_texture(
         2.0,
         [0.7, 0.1, 0.0, 0.0],
@@ -292,168 +597,178 @@ fn main() {
         0.0,                        
     );
 
-    let skybox_texture = Arc::new(Texture::new("assets/snowy.jpg"));
-
-    let mut objects: Vec<Box<dyn RayIntersect>> = Vec::new();
-    let rows = 9;
-    let cols = 9;
-    let size = 2.0;
-    let x_offset = -(cols as f32) * size / 2.0;
-    let z_offset = -(rows as f32) * size / 2.0;
-
-    for row in 0..rows {
-        for col in 0..cols {
-            let x = x_offset + col as f32 * size;
-            let z = z_offset + row as f32 * size;
-
-            
-            let material = if random::<f32>() < 0.05 {
-                glowstone_material.clone()
-            } else if random::<bool>() {
-                snow_material.clone()
-            } else {
-                stone_material.clone()
-            };
-
-            let cube = Cube {
-                min: Vec3::new(x, -size / 2.0, z),
-                max: Vec3::new(x + size, size / 2.0, z + size),
-                material,
-            };
-            objects.push(Box::new(cube));
+    let mut materials = HashMap::from([
+        ("snow", snow_material),
+        ("stone", stone_material),
+        ("ice", ice_material),
+        ("packed_ice", packed_ice_material),
+        ("glowstone", glowstone_material),
+    ]);
+    let atlas_materials = scene.atlas_materials().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    for (name, material) in &atlas_materials {
+        if materials.insert(name.as_str(), material.clone()).is_some() {
+            eprintln!("atlas tile '{}' has the same name as a built-in material", name);
+            std::process::exit(1);
         }
     }
+    if let Err(e) = scene.apply_materials(&mut materials) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let pattern_positions_level_1_and_2 = vec![
-        (2, 2),
-        (2, 3),
-        (2, 4),
-        (2, 5),
-        (2, 6),
-        (3, 1),
-        (3, 7),
-        (4, 1),
-        (4, 7),
-        (5, 1),
-        (5, 7),
-        (6, 2),
-        (6, 3),
-        (6, 5),
-        (6, 6),
-    ];
-
-    let pattern_positions_level_3 = vec![
-        (2, 3),
-        (2, 4),
-        (2, 5),
-        (3, 2),
-        (3, 3),
-        (3, 4),
-        (3, 5),
-        (3, 6),
-        (4, 1),
-        (4, 2),
-        (4, 3),
-        (4, 4),
-        (4, 5),
-        (4, 6),
-        (4, 7),
-        (5, 2),
-        (5, 3),
-        (5, 4),
-        (5, 5),
-        (5, 6),
-        (6, 3),
-        (6, 4),
-        (6, 5),
-    ];
-
-    let pattern_positions_level_4 = vec![(3, 4), (4, 3), (4, 4), (4, 5), (5, 4)];
+    let skybox_texture = Arc::new(Texture::new("assets/snowy.jpg"));
 
-    struct LevelPattern {
-        y_level: f32,
-        positions: Vec<(usize, usize)>,
+    let size = 2.0;
+    // Structures stand on the ground, with cell [0, 0, 0] resting on its center block.
+    let ground = Vec3::new(0.0, size / 2.0, 0.0);
+
+    let mut platform = SceneNode::new("platform");
+    for (cell, kind) in scene.terrain.cells(&mut rng) {
+        let (min, max) = structure::cell_bounds(cell, ground, size);
+        let cube = match kind {
+            Ground::Glowstone => Cube::new(min, max, materials["glowstone"].clone()),
+            Ground::Snow => Cube::new(min, max, materials["snow"].clone()),
+            // Snow-covered stone: snow on top, stone showing on the sides.
+            Ground::SnowyStone => Cube::with_top_side_bottom(
+                min,
+                max,
+                materials["snow"].clone(),
+                materials["stone"].clone(),
+                materials["stone"].clone(),
+            ),
+            Ground::Stone => Cube::new(min, max, materials["stone"].clone()),
+        };
+        platform.add_block(cube);
     }
 
-    let levels = vec![
-        LevelPattern {
-            y_level: size / 2.0,
-            positions: pattern_positions_level_1_and_2.clone(),
-        },
-        LevelPattern {
-            y_level: size * 1.5,
-            positions: pattern_positions_level_1_and_2.clone(),
-        },
-        LevelPattern {
-            y_level: size * 2.5,
-            positions: pattern_positions_level_3.clone(),
-        },
-        LevelPattern {
-            y_level: size * 3.4,
-            positions: pattern_positions_level_4.clone(),
-        },
-    ];
+    let mut igloo = SceneNode::new("igloo");
+    for cube in structure::blocks(&scene.igloo.cells(), ground, size, &materials["packed_ice"], &materials["ice"]) {
+        igloo.add_block(cube);
+    }
 
-    for level in levels {
-        for (row, col) in &level.positions {
-            let x = x_offset + *col as f32 * size;
-            let z = z_offset + *row as f32 * size;
-    
-            
-            let material = if (row + col) % 2 == 0 {
-                packed_ice_material.clone()
-            } else {
-                ice_material.clone()
-            };
-    
-            let cube = Cube {
-                min: Vec3::new(x, level.y_level, z),
-                max: Vec3::new(x + size, level.y_level + size, z + size),
-                material,
-            };
-            objects.push(Box::new(cube));
+    let mut root = SceneNode::new("scene");
+    root.add_child(platform);
+    root.add_child(igloo);
+
+    let generated = scene
+        .structure_nodes(ground, size, &materials)
+        .and_then(|mut nodes| {
+            let (models, cameras) = scene.model_nodes(ground, size, &materials)?;
+            nodes.extend(models);
+            Ok((nodes, cameras))
+        });
+    let imported_cameras = match generated {
+        Ok((nodes, cameras)) => {
+            nodes.into_iter().for_each(|node| root.add_child(node));
+            cameras
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    }    
+    };
+    let mut objects = scene
+        .apply_nodes(&mut root, &materials)
+        .and_then(|()| root.flatten())
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
 
     let mut camera = Camera::new(
         Vec3::new(0.0, 15.0, 30.0),
         Vec3::new(0.0, 0.0, 0.0),
         Vec3::new(0.0, 1.0, 0.0),
+    )
+    .with_lens(
+        Lens::from_f_stop(1.2, 2.0, 33.5)
+            .with_bokeh(Bokeh::Polygon {
+                blades: 6,
+                rotation: 0.0,
+            })
+            .with_samples(8),
     );
-    let rotation_speed = PI / 50.0;
-    let zoom_speed = 0.1;
-
-    let light1 = Light::new(Vec3::new(20.0, 30.0, 20.0), Color::new(150, 180, 255), 15.0);
+    // The lens starts closed; L opens it to this radius for depth of field.
+    let open_aperture = camera.lens.aperture_radius;
+    camera.set_aperture_radius(0.0);
+    // Speeds are per second so controls feel the same however long a frame takes.
+    let rotation_speed = PI / 2.0;
+    let zoom_speed = 6.0;
+    let fly_speed = 8.0;
+    let mouse_sensitivity = 0.005;
+    let fov_speed = PI / 6.0;
+    let focus_speed = 10.0;
+    let projections = [
+        Projection::Perspective,
+        Projection::Orthographic { height: 30.0 },
+        Projection::Fisheye {
+            mapping: FisheyeMapping::Equidistant,
+        },
+        Projection::Fisheye {
+            mapping: FisheyeMapping::Equisolid,
+        },
+        Projection::Equirectangular,
+    ];
+    let mut projection_index = 0;
+    if let Some(pose) = camera_path.as_ref().and_then(|camera_path| camera_path.sample(0.0)) {
+        camera.set_pose(&pose);
+    } else if let Some(imported) = imported_cameras.first() {
+        // Without a camera path, look through the first camera of an imported glTF scene.
+        camera.set_pose(&imported.pose);
+        camera.set_projection(imported.projection);
+    }
+    // Wider than human eyes so the depth reads on a diorama this size.
+    let stereo = Stereo::new(0.5, 33.5);
+
+    // Shadow rays stop all of an opaque blocker's light and tint what passes through
+    // ice, so these stay low: at the 15 and 10 they once had, to make up for shadows
+    // that never went below 10% light, every lit face clips to white and the blue
+    // through the igloo walls can't show.
+    let light1 = Light::new(Vec3::new(20.0, 30.0, 20.0), Color::new(150, 180, 255), 1.5);
     let light2 = Light::new(
         Vec3::new(-20.0, 30.0, -20.0),
         Color::new(180, 180, 255),
-        10.0,
+        1.0,
     );
     let lights = vec![light1, light2];
 
-    
-    let mut emissive_lights: Vec<Light> = Vec::new();
-    for obj in &objects {
-        if let Some(cube) = obj.as_any().downcast_ref::<Cube>() {
-            if cube.material.emission_intensity > 0.0 {
-                let position = (cube.min + cube.max) * 0.5;
-                let light = Light::new(
-                    position,
-                    cube.material.emission_color,
-                    cube.material.emission_intensity,
-                );
-                emissive_lights.push(light);
-            }
+    let mut all_lights = scene_lights(&root, &objects, &lights);
+    let mut is_animated = any_animated(&objects);
+
+    if let Some(path) = &options.export {
+        if let Err(e) = export::export(&root, path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+        println!("Exported {}", path);
+        return;
     }
 
-    let mut all_lights = lights.clone(); 
-    all_lights.extend(emissive_lights);
+    if let Some(frames) = options.frames {
+        let object_refs: Vec<&dyn RayIntersect> = objects.iter().map(|obj| obj.as_ref()).collect();
+        if let Err(e) = render_sequence(
+            frames,
+            &options.output,
+            &scene,
+            &mut camera,
+            camera_path.as_ref(),
+            &object_refs,
+            &all_lights,
+            &skybox_texture,
+        ) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let window_width = 800;
-    let window_height = 600;
-    let framebuffer_width = 800;
-    let framebuffer_height = 600;
+    let window_width = scene.render.width;
+    let window_height = scene.render.height;
+    let framebuffer_width = scene.render.width;
+    let framebuffer_height = scene.render.height;
     let frame_delay = Duration::from_millis(16);
 
     let mut framebuffer = Framebuffer::new(framebuffer_width, framebuffer_height);
@@ -468,40 +783,275 @@ fn main() {
     window.set_position(500, 500);
     window.update();
 
+    let start_time = Instant::now();
+    let mut last_frame = start_time;
+    let mut last_mouse_pos: Option<(f32, f32)> = None;
+    let mut playback_start: Option<Instant> = None;
+    // Shutter used when motion blur is switched on with B; camera blur covers the
+    // movement since the previous frame.
+    let motion_blur_shutter = Shutter::new(1.0 / 24.0, 8);
+
     while window.is_open() {
         if window.is_key_down(Key::Escape) {
             break;
         }
 
+        // V hides the platform, I the igloo, and the numpad arrows slide the igloo a block at a time.
+        let mut scene_changed = false;
+        for (key, name) in [(Key::V, "platform"), (Key::I, "igloo")] {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                if let Some(node) = root.find_mut(name) {
+                    node.visible = !node.visible;
+                    scene_changed = true;
+                }
+            }
+        }
+        let slides = [
+            (Key::NumPad4, Vec3::new(-size, 0.0, 0.0)),
+            (Key::NumPad6, Vec3::new(size, 0.0, 0.0)),
+            (Key::NumPad8, Vec3::new(0.0, 0.0, -size)),
+            (Key::NumPad2, Vec3::new(0.0, 0.0, size)),
+        ];
+        for (key, offset) in slides {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                if let Some(igloo) = root.find_mut("igloo") {
+                    igloo.transform = nalgebra_glm::translation(&offset) * igloo.transform;
+                    scene_changed = true;
+                }
+            }
+        }
+        if scene_changed {
+            match root.flatten() {
+                Ok(placed) => objects = placed,
+                Err(e) => eprintln!("{}", e),
+            }
+            all_lights = scene_lights(&root, &objects, &lights);
+            is_animated = any_animated(&objects);
+        }
+
         let object_refs: Vec<&dyn RayIntersect> = objects.iter().map(|obj| obj.as_ref()).collect();
 
-        if window.is_key_down(Key::Left) {
-            camera.orbit(rotation_speed, 0.0);
+        let pose_before = camera.pose();
+        let now = Instant::now();
+        // Capped so a slow frame (or an export) doesn't fling the camera.
+        let dt = now.duration_since(last_frame).as_secs_f32().min(0.25);
+        last_frame = now;
+
+        if window.is_key_pressed(Key::C, KeyRepeat::No) {
+            camera.mode = match camera.mode {
+                CameraMode::Orbit => CameraMode::FreeFly,
+                CameraMode::FreeFly => CameraMode::Orbit,
+            };
         }
-        if window.is_key_down(Key::Right) {
-            camera.orbit(-rotation_speed, 0.0);
+
+        // K starts or stops playing the scene's camera path on a loop.
+        if window.is_key_pressed(Key::K, KeyRepeat::No) && camera_path.is_some() {
+            playback_start = match playback_start {
+                Some(_) => None,
+                None => Some(now),
+            };
         }
-        if window.is_key_down(Key::Up) {
-            camera.orbit(0.0, -rotation_speed);
+        if let (Some(started), Some(camera_path)) = (playback_start, &camera_path) {
+            let duration = camera_path.duration();
+            let elapsed = now.duration_since(started).as_secs_f32();
+            let time = if duration > 0.0 { elapsed % duration } else { 0.0 };
+            if let Some(pose) = camera_path.sample(time) {
+                camera.set_pose(&pose);
+            }
         }
-        if window.is_key_down(Key::Down) {
-            camera.orbit(0.0, rotation_speed);
+
+        // Mouse look only while the right button is held, so moving the cursor
+        // over the window or towards another one leaves the view alone.
+        let mouse_pos = window
+            .get_mouse_down(MouseButton::Right)
+            .then(|| window.get_mouse_pos(MouseMode::Pass))
+            .flatten();
+
+        match camera.mode {
+            CameraMode::Orbit => {
+                let rotation = rotation_speed * dt;
+                if window.is_key_down(Key::Left) {
+                    camera.orbit(rotation, 0.0);
+                }
+                if window.is_key_down(Key::Right) {
+                    camera.orbit(-rotation, 0.0);
+                }
+                if window.is_key_down(Key::Up) {
+                    camera.orbit(0.0, -rotation);
+                }
+                if window.is_key_down(Key::Down) {
+                    camera.orbit(0.0, rotation);
+                }
+
+                if window.is_key_down(Key::Q) {
+                    camera.zoom(zoom_speed * dt);
+                }
+                if window.is_key_down(Key::E) {
+                    camera.zoom(-zoom_speed * dt);
+                }
+            }
+            CameraMode::FreeFly => {
+                let mut speed = fly_speed * dt;
+                if window.is_key_down(Key::LeftCtrl) {
+                    speed *= 4.0;
+                }
+                if window.is_key_down(Key::LeftAlt) {
+                    speed *= 0.25;
+                }
+
+                let key_axis = |positive: Key, negative: Key| {
+                    window.is_key_down(positive) as i32 as f32 - window.is_key_down(negative) as i32 as f32
+                };
+                let forward = key_axis(Key::W, Key::S);
+                let right = key_axis(Key::D, Key::A);
+                let up = key_axis(Key::Space, Key::LeftShift);
+                if forward != 0.0 || right != 0.0 || up != 0.0 {
+                    camera.fly(forward * speed, right * speed, up * speed);
+                }
+
+                if let (Some((x, y)), Some((last_x, last_y))) = (mouse_pos, last_mouse_pos) {
+                    let (dx, dy) = (x - last_x, y - last_y);
+                    if dx != 0.0 || dy != 0.0 {
+                        camera.look(dx * mouse_sensitivity, -dy * mouse_sensitivity);
+                    }
+                }
+            }
+        }
+        last_mouse_pos = mouse_pos;
+
+        if window.is_key_down(Key::Z) {
+            camera.adjust_fov(-fov_speed * dt);
+        }
+        if window.is_key_down(Key::X) {
+            camera.adjust_fov(fov_speed * dt);
         }
 
-        if window.is_key_down(Key::Q) {
-            camera.zoom(zoom_speed);
+        if window.is_key_pressed(Key::L, KeyRepeat::No) {
+            let radius = if camera.lens.is_pinhole() { open_aperture } else { 0.0 };
+            camera.set_aperture_radius(radius);
         }
-        if window.is_key_down(Key::E) {
-            camera.zoom(-zoom_speed);
+        if window.is_key_down(Key::LeftBracket) {
+            camera.set_focus_distance(camera.lens.focus_distance - focus_speed * dt);
+        }
+        if window.is_key_down(Key::RightBracket) {
+            camera.set_focus_distance(camera.lens.focus_distance + focus_speed * dt);
+        }
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            camera.autofocus = !camera.autofocus;
+        }
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            projection_index = (projection_index + 1) % projections.len();
+            camera.set_projection(projections[projection_index]);
+        }
+
+        // F11 exports a 2:1 panorama from the camera position for 360° viewers.
+        if window.is_key_pressed(Key::F11, KeyRepeat::No) {
+            let projection = camera.projection;
+            camera.projection = Projection::Equirectangular;
+            let mut panorama = Framebuffer::new(2048, 1024);
+            render(
+                &mut panorama,
+                &object_refs,
+                &camera,
+                &all_lights,
+                &skybox_texture,
+                start_time.elapsed().as_secs_f32(),
+            );
+            camera.projection = projection;
+            match panorama.save("panorama.png") {
+                Ok(()) => println!("Saved panorama.png"),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        // F9 exports the scene as it stands, with any hidden nodes left out.
+        if window.is_key_pressed(Key::F9, KeyRepeat::No) {
+            for path in ["scene.obj", "scene.ply"] {
+                match export::export(&root, path) {
+                    Ok(()) => println!("Exported {}", path),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+
+        // F10 exports the current view as a stereo pair in every layout. With the
+        // equirectangular projection the pair is an omni-directional stereo panorama.
+        if window.is_key_pressed(Key::F10, KeyRepeat::No) {
+            let (width, height) = if camera.projection == Projection::Equirectangular {
+                (2048, 1024)
+            } else {
+                (framebuffer_width, framebuffer_height)
+            };
+            let time = start_time.elapsed().as_secs_f32();
+            let views: Vec<Framebuffer> = [Eye::Left, Eye::Right]
+                .iter()
+                .map(|&eye| {
+                    let mut view = Framebuffer::new(width, height);
+                    let eye_camera = camera.eye_camera(eye, &stereo);
+                    render(&mut view, &object_refs, &eye_camera, &all_lights, &skybox_texture, time);
+                    view
+                })
+                .collect();
+
+            let exports = [
+                (StereoLayout::SideBySide, "stereo_side_by_side.png"),
+                (StereoLayout::TopBottom, "stereo_top_bottom.png"),
+                (StereoLayout::Anaglyph, "stereo_anaglyph.png"),
+            ];
+            for (layout, path) in exports {
+                match Framebuffer::stereo(&views[0], &views[1], layout).save(path) {
+                    Ok(()) => println!("Saved {}", path),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+
+        if window.is_key_pressed(Key::B, KeyRepeat::No) {
+            camera.shutter = if camera.shutter.is_open() {
+                Shutter::instant()
+            } else {
+                motion_blur_shutter
+            };
+            camera.clear_shutter_motion();
+        }
+        // Blur along this frame's move only; a camera at rest is drawn sharp.
+        if camera.shutter.is_open() {
+            let pose = camera.pose();
+            if pose.position != pose_before.position || pose.target != pose_before.target {
+                camera.set_shutter_motion(pose_before, pose);
+            } else {
+                camera.clear_shutter_motion();
+            }
+        }
+
+        let mut camera_changed = camera.is_changed();
+        if window.get_mouse_down(MouseButton::Left) {
+            let focus = window.get_mouse_pos(MouseMode::Discard).and_then(|(x, y)| {
+                camera.focus_distance_at(x, y, framebuffer_width, framebuffer_height, &object_refs)
+            });
+            if let Some(focus_distance) = focus {
+                camera.autofocus = false;
+                camera.lens.focus_distance = focus_distance;
+                camera_changed = true;
+            }
+        }
+        if camera.autofocus && camera_changed {
+            let (x, y) = (framebuffer_width as f32 * 0.5, framebuffer_height as f32 * 0.5);
+            if let Some(focus_distance) =
+                camera.focus_distance_at(x, y, framebuffer_width, framebuffer_height, &object_refs)
+            {
+                camera.lens.focus_distance = focus_distance;
+            }
         }
 
-        if camera.is_changed() {
+        if camera_changed || is_animated || scene_changed {
             render(
                 &mut framebuffer,
                 &object_refs,
                 &camera,
                 &all_lights,
                 &skybox_texture,
+                start_time.elapsed().as_secs_f32(),
             );
         }
 
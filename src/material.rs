@@ -1,8 +1,67 @@
-use nalgebra_glm::Vec3;
-use std::sync::Arc;
+use nalgebra_glm::{Mat2, Vec2, Vec3};
 
+use crate::animation::IntensityCurve;
+use crate::atlas::{AtlasTile, TextureAtlas};
 use crate::color::Color;
-use crate::texture::Texture;
+use crate::procedural::TextureSpace;
+use crate::ray_intersect::Intersect;
+use crate::texture::TextureSource;
+
+/// Transform applied to surface coordinates before texture lookups:
+/// `uv' = rotate(rotation) * (scale * uv) + offset`, rotating about the uv origin.
+/// With `world_space` set, `uv` is first measured in world units along the face,
+/// so `scale` becomes a tiling density (repeats per unit) independent of object size.
+#[derive(Debug, Clone, Copy)]
+pub struct UvTransform {
+    pub scale: Vec2,
+    pub offset: Vec2,
+    pub rotation: f32,
+    pub world_space: bool,
+}
+
+impl UvTransform {
+    pub fn identity() -> Self {
+        UvTransform {
+            scale: Vec2::new(1.0, 1.0),
+            offset: Vec2::new(0.0, 0.0),
+            rotation: 0.0,
+            world_space: false,
+        }
+    }
+
+    /// Tiles the texture `density` times per world unit along both face axes.
+    pub fn world_space(density: f32) -> Self {
+        UvTransform {
+            scale: Vec2::new(density, density),
+            world_space: true,
+            ..UvTransform::identity()
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        !self.world_space
+            && self.scale == Vec2::new(1.0, 1.0)
+            && self.offset == Vec2::new(0.0, 0.0)
+            && self.rotation == 0.0
+    }
+
+    /// Linear part of the transform for a face whose uv axes span `face_size` world units.
+    pub fn matrix(&self, face_size: Vec2) -> Mat2 {
+        let (sin, cos) = self.rotation.sin_cos();
+        let rotation = Mat2::new(cos, -sin, sin, cos);
+        let mut scale = self.scale;
+        if self.world_space {
+            scale.component_mul_assign(&face_size);
+        }
+        rotation * Mat2::new(scale.x, 0.0, 0.0, scale.y)
+    }
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        UvTransform::identity()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Material {
@@ -12,10 +71,25 @@ pub struct Material {
     pub refractive_index: f32,
     pub has_texture: bool,
     pub has_normal_map: bool,
-    pub texture: Option<Arc<Texture>>,     
-    pub normal_map: Option<Arc<Texture>>,   
+    pub texture: Option<TextureSource>,     
+    pub normal_map: Option<TextureSource>,   
+    pub roughness_map: Option<TextureSource>,
+    /// Grayscale height field (white is high) used for bump and parallax mapping.
+    pub height_map: Option<TextureSource>,
+    /// Depth of the relief in world units.
+    pub height_scale: f32,
+    /// Steps taken by parallax occlusion mapping; 0 keeps bump mapping only.
+    pub parallax_layers: u32,
+    /// Alpha test: surface points whose texel alpha is below this are treated as holes.
+    pub alpha_cutoff: Option<f32>,
     pub emission_color: Color,        
     pub emission_intensity: f32,      
+    /// Scales `emission_intensity` over scene time, e.g. to make glowstone flicker.
+    pub emission_curve: Option<IntensityCurve>,
+    pub uv_transform: UvTransform,
+    /// Region of an atlas the texture, normal and height maps are read from; their
+    /// sheets share the atlas layout.
+    pub atlas_tile: Option<AtlasTile>,
 }
 
 impl Material {
@@ -36,8 +110,16 @@ impl Material {
             has_normal_map: false,
             texture: None,
             normal_map: None,
+            roughness_map: None,
+            height_map: None,
+            height_scale: 0.0,
+            parallax_layers: 0,
+            alpha_cutoff: None,
             emission_color,
             emission_intensity,
+            emission_curve: None,
+            uv_transform: UvTransform::identity(),
+            atlas_tile: None,
         }
     }
 
@@ -45,8 +127,8 @@ impl Material {
         specular: f32,
         albedo: [f32; 4],
         refractive_index: f32,
-        texture: Arc<Texture>,
-        normal_map: Option<Arc<Texture>>, 
+        texture: impl Into<TextureSource>,
+        normal_map: Option<TextureSource>, 
         emission_color: Color,        
         emission_intensity: f32,      
     ) -> Self {
@@ -57,34 +139,271 @@ impl Material {
             refractive_index,
             has_texture: true,
             has_normal_map: normal_map.is_some(),
-            texture: Some(texture), 
+            texture: Some(texture.into()), 
             normal_map,             
+            roughness_map: None,
+            height_map: None,
+            height_scale: 0.0,
+            parallax_layers: 0,
+            alpha_cutoff: None,
             emission_color,
             emission_intensity,
+            emission_curve: None,
+            uv_transform: UvTransform::identity(),
+            atlas_tile: None,
         }
     }
 
-    pub fn get_diffuse_color(&self, u: f32, v: f32) -> Color {
-        if let Some(texture) = &self.texture {
-            texture.get_color(u, v)
-        } else {
-            self.diffuse
+    pub fn with_uv_transform(mut self, uv_transform: UvTransform) -> Self {
+        self.uv_transform = uv_transform;
+        self
+    }
+
+    /// Uses the named tile of `atlas` as this material's texture.
+    pub fn with_atlas_tile(mut self, atlas: &TextureAtlas, tile_name: &str) -> Result<Self, String> {
+        let tile = atlas
+            .tile(tile_name)
+            .ok_or_else(|| format!("atlas has no tile named '{}'", tile_name))?;
+        self.texture = Some(TextureSource::Image(atlas.texture.clone()));
+        self.has_texture = true;
+        self.atlas_tile = Some(tile);
+        Ok(self)
+    }
+
+    pub fn with_normal_map(mut self, normal_map: impl Into<TextureSource>) -> Self {
+        self.normal_map = Some(normal_map.into());
+        self.has_normal_map = true;
+        self
+    }
+
+    /// Roughness in `[0, 1]` read from the map's brightness; rough spots get a wider, dimmer highlight.
+    pub fn with_roughness_map(mut self, roughness_map: impl Into<TextureSource>) -> Self {
+        self.roughness_map = Some(roughness_map.into());
+        self
+    }
+
+    pub fn with_height_map(
+        mut self,
+        height_map: impl Into<TextureSource>,
+        height_scale: f32,
+        parallax_layers: u32,
+    ) -> Self {
+        self.height_map = Some(height_map.into());
+        self.height_scale = height_scale;
+        self.parallax_layers = parallax_layers;
+        self
+    }
+
+    pub fn with_alpha_cutoff(mut self, alpha_cutoff: f32) -> Self {
+        self.alpha_cutoff = Some(alpha_cutoff);
+        self
+    }
+
+    pub fn with_emission_curve(mut self, emission_curve: IntensityCurve) -> Self {
+        self.emission_curve = Some(emission_curve);
+        self
+    }
+
+    pub fn get_emission_intensity(&self, time: f32) -> f32 {
+        match &self.emission_curve {
+            Some(curve) => self.emission_intensity * curve.factor(time),
+            None => self.emission_intensity,
+        }
+    }
+
+    /// Whether the material changes over time and the scene must be re-rendered every frame.
+    pub fn is_animated(&self) -> bool {
+        let animated_texture = matches!(
+            &self.texture,
+            Some(TextureSource::Image(texture)) if texture.frame_count() > 1
+        );
+        animated_texture || self.emission_curve.is_some()
+    }
+
+    pub fn get_alpha(&self, u: f32, v: f32, time: f32) -> f32 {
+        match (&self.texture, &self.atlas_tile) {
+            (Some(TextureSource::Image(texture)), Some(tile)) => {
+                let (u, v, _) = tile.map(u, v, &Default::default());
+                texture.get_alpha_in_tile(u, v, time, tile)
+            }
+            (Some(TextureSource::Image(texture)), None) => texture.get_alpha(u, v, time),
+            _ => 1.0,
+        }
+    }
+
+    /// Whether the alpha test discards the surface at `(u, v)` at scene time `time`.
+    pub fn is_cut_out(&self, u: f32, v: f32, time: f32) -> bool {
+        match self.alpha_cutoff {
+            Some(cutoff) => self.get_alpha(u, v, time) < cutoff,
+            None => false,
+        }
+    }
+
+    pub fn get_diffuse_color(&self, intersect: &Intersect) -> Color {
+        match (&self.texture, &self.atlas_tile) {
+            (Some(TextureSource::Image(texture)), Some(tile)) => {
+                let (u, v, derivatives) =
+                    tile.map(intersect.u, intersect.v, &intersect.uv_derivatives);
+                texture.sample_in_tile(u, v, &derivatives, intersect.time, tile)
+            }
+            (Some(texture), _) => texture.sample(intersect),
+            (None, _) => self.diffuse,
+        }
+    }
+
+    /// Tangent-space normal: `x` along `dpdu`, `y` along `dpdv`, `z` along the surface normal.
+    pub fn get_normal_from_map(&self, intersect: &Intersect) -> Vec3 {
+        match &self.normal_map {
+            Some(TextureSource::Image(normal_map)) => {
+                let color = match &self.atlas_tile {
+                    Some(tile) => {
+                        let (u, v, derivatives) =
+                            tile.map(intersect.u, intersect.v, &intersect.uv_derivatives);
+                        normal_map.sample_in_tile(u, v, &derivatives, intersect.time, tile)
+                    }
+                    None => normal_map.sample(
+                        intersect.u,
+                        intersect.v,
+                        &intersect.uv_derivatives,
+                        intersect.time,
+                    ),
+                };
+
+                
+                let nx = (color.r as f32 / 255.0) * 2.0 - 1.0;
+                let ny = (color.g as f32 / 255.0) * 2.0 - 1.0;
+                let nz = color.b as f32 / 255.0; 
+
+                Vec3::new(nx, ny, nz).normalize()
+            }
+            Some(TextureSource::Procedural(height)) => {
+                // Treat the pattern as a height field and tilt the normal against its slope.
+                let gradient = height.gradient(&intersect.texture_point(height.space));
+                let (slope_u, slope_v) = match height.space {
+                    TextureSpace::Uv => (
+                        gradient.x / intersect.dpdu.magnitude().max(1e-6),
+                        gradient.y / intersect.dpdv.magnitude().max(1e-6),
+                    ),
+                    _ => (
+                        gradient.dot(&intersect.dpdu.normalize()),
+                        gradient.dot(&intersect.dpdv.normalize()),
+                    ),
+                };
+
+                Vec3::new(
+                    -slope_u * height.bump_strength,
+                    -slope_v * height.bump_strength,
+                    1.0,
+                )
+                .normalize()
+            }
+            None => Vec3::new(0.0, 0.0, 1.0),
+        }
+    }
+
+    /// World-space normal used for shading, with the normal map and height map bumps applied.
+    pub fn get_shading_normal(&self, intersect: &Intersect) -> Vec3 {
+        if intersect.dpdu.magnitude_squared() == 0.0 || intersect.dpdv.magnitude_squared() == 0.0 {
+            return intersect.normal;
+        }
+
+        let tangent = intersect.dpdu.normalize();
+        let bitangent = intersect.dpdv.normalize();
+
+        let mut normal = intersect.normal;
+        if self.has_normal_map {
+            let mapped = self.get_normal_from_map(intersect);
+            normal = (tangent * mapped.x + bitangent * mapped.y + intersect.normal * mapped.z)
+                .normalize();
+        }
+
+        if let Some(height_map) = &self.height_map {
+            // Height slope per world unit along each face axis, by central differences.
+            let tile = self.atlas_tile.as_ref();
+            let (du, dv) = height_map.texel_size(tile);
+            let dh_du = (height_map.height_at(intersect, du, 0.0, tile)
+                - height_map.height_at(intersect, -du, 0.0, tile))
+                / (2.0 * du);
+            let dh_dv = (height_map.height_at(intersect, 0.0, dv, tile)
+                - height_map.height_at(intersect, 0.0, -dv, tile))
+                / (2.0 * dv);
+            let slope_t = dh_du / intersect.dpdu.magnitude() * self.height_scale;
+            let slope_b = dh_dv / intersect.dpdv.magnitude() * self.height_scale;
+
+            normal = (normal - tangent * slope_t - bitangent * slope_b).normalize();
         }
+
+        normal
     }
 
-    pub fn get_normal_from_map(&self, u: f32, v: f32) -> Vec3 {
-        if let Some(normal_map) = &self.normal_map {
-            let color = normal_map.get_color(u, v);
+    /// Parallax occlusion mapping: marches the view ray through the height field
+    /// below the face and returns the uv where it first dips under the surface.
+    pub fn get_parallax_uv(&self, intersect: &Intersect, view_dir: &Vec3) -> (f32, f32) {
+        let height_map = match &self.height_map {
+            Some(height_map) if self.parallax_layers > 0 && self.height_scale > 0.0 => height_map,
+            _ => return (intersect.u, intersect.v),
+        };
+
+        let dpdu_len = intersect.dpdu.magnitude();
+        let dpdv_len = intersect.dpdv.magnitude();
+        if dpdu_len == 0.0 || dpdv_len == 0.0 {
+            return (intersect.u, intersect.v);
+        }
+
+        let view_t = view_dir.dot(&(intersect.dpdu / dpdu_len));
+        let view_b = view_dir.dot(&(intersect.dpdv / dpdv_len));
+        let view_n = view_dir.dot(&intersect.normal).max(0.05);
 
-            
-            let nx = (color.r as f32 / 255.0) * 2.0 - 1.0;
-            let ny = (color.g as f32 / 255.0) * 2.0 - 1.0;
-            let nz = color.b as f32 / 255.0; 
+        let layers = self.parallax_layers as f32;
+        let layer_depth = 1.0 / layers;
+        let step_u = view_t / view_n * self.height_scale / dpdu_len / layers;
+        let step_v = view_b / view_n * self.height_scale / dpdv_len / layers;
 
-            Vec3::new(nx, ny, nz).normalize()
+        let tile = self.atlas_tile.as_ref();
+        let depth_at = |du: f32, dv: f32| 1.0 - height_map.height_at(intersect, du, dv, tile);
+
+        let (mut du, mut dv) = (0.0, 0.0);
+        let mut current_depth = 0.0;
+        let mut surface_depth = depth_at(du, dv);
+        let mut steps = 0;
+        while current_depth < surface_depth && steps < self.parallax_layers {
+            du -= step_u;
+            dv -= step_v;
+            current_depth += layer_depth;
+            surface_depth = depth_at(du, dv);
+            steps += 1;
+        }
+
+        // Blend between the last two layers for a smoother hit.
+        let after = surface_depth - current_depth;
+        let before = depth_at(du + step_u, dv + step_v) - (current_depth - layer_depth);
+        let weight = if (after - before).abs() > 1e-6 {
+            (after / (after - before)).clamp(0.0, 1.0)
         } else {
-            Vec3::new(0.0, 0.0, 1.0) 
+            0.0
+        };
+
+        (
+            intersect.u + du + step_u * weight,
+            intersect.v + dv + step_v * weight,
+        )
+    }
+
+    pub fn get_roughness(&self, intersect: &Intersect) -> f32 {
+        match &self.roughness_map {
+            Some(map) => map.sample(intersect).luminance().clamp(0.0, 1.0),
+            None => 0.0,
+        }
+    }
+
+    /// Phong exponent after roughness: `specular` on smooth spots, dropping towards 1 as
+    /// roughness rises. Without a roughness map it is `specular` as given.
+    pub fn get_specular_exponent(&self, intersect: &Intersect) -> f32 {
+        if self.roughness_map.is_none() {
+            return self.specular;
         }
+        let smoothness = 1.0 - self.get_roughness(intersect);
+        1.0 + (self.specular - 1.0).max(0.0) * smoothness * smoothness
     }
 
     pub fn black() -> Self {
@@ -97,8 +416,16 @@ impl Material {
             has_normal_map: false,
             texture: None,
             normal_map: None,
+            roughness_map: None,
+            height_map: None,
+            height_scale: 0.0,
+            parallax_layers: 0,
+            alpha_cutoff: None,
             emission_color: Color::new(0, 0, 0),        
             emission_intensity: 0.0,      
+            emission_curve: None,
+            uv_transform: UvTransform::identity(),
+            atlas_tile: None,
         }
     }
 }
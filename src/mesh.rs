@@ -0,0 +1,388 @@
+use nalgebra_glm::{Vec2, Vec3};
+
+use crate::material::Material;
+use crate::ray_intersect::{Intersect, RayIntersect};
+
+/// Triangles per leaf of the bounding volume hierarchy.
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct Triangle {
+    pub positions: [Vec3; 3],
+    /// Per-vertex normals for smooth shading; the face normal is used without them.
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: [Vec2; 3],
+}
+
+impl Triangle {
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let [a, b, c] = self.positions;
+        (a.inf(&b).inf(&c), a.sup(&b).sup(&c))
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.positions[0] + self.positions[1] + self.positions[2]) / 3.0
+    }
+
+    /// Change of position per unit of `u` and `v` across the triangle; the edges
+    /// themselves when the texture coordinates are degenerate.
+    pub fn surface_derivatives(&self) -> (Vec3, Vec3) {
+        // Solve edge = dpdu * du + dpdv * dv for the two edges leaving vertex a.
+        let [a, b, c] = self.positions;
+        let [uv0, uv1, uv2] = self.uvs;
+        let (edge1, edge2) = (b - a, c - a);
+        let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        if det.abs() > 1e-12 {
+            (
+                (edge1 * duv2.y - edge2 * duv1.y) / det,
+                (edge2 * duv1.x - edge1 * duv2.x) / det,
+            )
+        } else {
+            (edge1, edge2)
+        }
+    }
+
+    /// Möller-Trumbore: distance along the ray and barycentric `(b1, b2)` of the hit.
+    fn intersect(&self, origin: &Vec3, direction: &Vec3) -> Option<(f32, f32, f32)> {
+        let [a, b, c] = self.positions;
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = direction.cross(&edge2);
+        let det = edge1.dot(&p);
+        if det.abs() < 1e-9 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = origin - a;
+        let b1 = s.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(&edge1);
+        let b2 = direction.dot(&q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(&q) * inv_det;
+        (t > 1e-4).then_some((t, b1, b2))
+    }
+}
+
+/// Node of the bounding volume hierarchy. Leaves hold `count` triangles from
+/// `first`; inner nodes have `count == 0` and their children at `first` and `first + 1`.
+#[derive(Debug, Clone)]
+struct BvhNode {
+    min: Vec3,
+    max: Vec3,
+    first: usize,
+    count: usize,
+}
+
+/// A triangle mesh with one material, e.g. a primitive imported from a model file.
+/// Triangles are kept in a bounding volume hierarchy so large meshes stay quick to trace.
+pub struct Mesh {
+    pub material: Material,
+    triangles: Vec<Triangle>,
+    nodes: Vec<BvhNode>,
+}
+
+impl Mesh {
+    pub fn new(triangles: Vec<Triangle>, material: Material) -> Self {
+        let mut mesh = Mesh {
+            material,
+            triangles,
+            nodes: Vec::new(),
+        };
+        if !mesh.triangles.is_empty() {
+            mesh.nodes.push(BvhNode {
+                min: Vec3::zeros(),
+                max: Vec3::zeros(),
+                first: 0,
+                count: mesh.triangles.len(),
+            });
+            mesh.subdivide(0);
+        }
+        mesh
+    }
+
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+
+    /// Fits node `index` around its triangles and splits it at the median of the
+    /// longest axis until leaves are small enough.
+    fn subdivide(&mut self, index: usize) {
+        let BvhNode { first, count, .. } = self.nodes[index];
+        let triangles = &mut self.triangles[first..first + count];
+
+        let (mut min, mut max) = triangles[0].bounds();
+        for triangle in triangles.iter() {
+            let (low, high) = triangle.bounds();
+            min = min.inf(&low);
+            max = max.sup(&high);
+        }
+        self.nodes[index].min = min;
+        self.nodes[index].max = max;
+        if count <= LEAF_SIZE {
+            return;
+        }
+
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let half = count / 2;
+        triangles.select_nth_unstable_by(half, |a, b| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            min,
+            max,
+            first,
+            count: half,
+        });
+        self.nodes.push(BvhNode {
+            min,
+            max,
+            first: first + half,
+            count: count - half,
+        });
+        self.nodes[index].first = left;
+        self.nodes[index].count = 0;
+        self.subdivide(left);
+        self.subdivide(left + 1);
+    }
+
+    fn hit(
+        &self,
+        triangle: &Triangle,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        time: f32,
+        hit: (f32, f32, f32),
+    ) -> Intersect {
+        let (t, b1, b2) = hit;
+        let b0 = 1.0 - b1 - b2;
+        let [a, b, c] = triangle.positions;
+        let [uv0, uv1, uv2] = triangle.uvs;
+        let point = ray_origin + ray_direction * t;
+        let uv = uv0 * b0 + uv1 * b1 + uv2 * b2;
+
+        let face_normal = (b - a).cross(&(c - a)).normalize();
+        let normal = match triangle.normals {
+            Some([n0, n1, n2]) => {
+                let normal = n0 * b0 + n1 * b1 + n2 * b2;
+                if normal.magnitude_squared() > 0.0 { normal.normalize() } else { face_normal }
+            }
+            None => face_normal,
+        };
+
+        let (dpdu, dpdv) = triangle.surface_derivatives();
+        Intersect::new(point, normal, t, self.material.clone(), uv.x, uv.y)
+            .with_surface_derivatives(dpdu, dpdv)
+            .with_time(time)
+            .apply_uv_transform()
+    }
+}
+
+impl Mesh {
+    /// Walks the hierarchy for the closest hit before `max_distance`, or with
+    /// `any_hit` for the first one found. Only the chosen hit is shaded, unless the
+    /// material's alpha test needs the texture to accept one.
+    fn trace(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+        any_hit: bool,
+    ) -> Option<Intersect> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = Vec3::new(1.0 / ray_direction.x, 1.0 / ray_direction.y, 1.0 / ray_direction.z);
+        let enters = |node: &BvhNode, closest: f32| {
+            let mut near = 0.0f32;
+            let mut far = closest;
+            for axis in 0..3 {
+                let t0 = (node.min[axis] - ray_origin[axis]) * inv_dir[axis];
+                let t1 = (node.max[axis] - ray_origin[axis]) * inv_dir[axis];
+                // NaN from a ray lying on a slab plane counts as inside the slab.
+                let (t0, t1) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+                if !t0.is_nan() {
+                    near = near.max(t0);
+                }
+                if !t1.is_nan() {
+                    far = far.min(t1);
+                }
+            }
+            near <= far
+        };
+
+        let alpha_tested = self.material.alpha_cutoff.is_some();
+        let mut closest = max_distance;
+        let mut best = None;
+        let mut stack = vec![0];
+        'walk: while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !enters(node, closest) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+                continue;
+            }
+
+            for triangle in &self.triangles[node.first..node.first + node.count] {
+                let Some(hit) = triangle.intersect(ray_origin, ray_direction) else {
+                    continue;
+                };
+                if hit.0 >= closest {
+                    continue;
+                }
+                if alpha_tested {
+                    let intersect = self.hit(triangle, ray_origin, ray_direction, time, hit);
+                    if intersect.material.is_cut_out(intersect.u, intersect.v, time) {
+                        continue;
+                    }
+                }
+                closest = hit.0;
+                best = Some((triangle, hit));
+                if any_hit {
+                    break 'walk;
+                }
+            }
+        }
+        best.map(|(triangle, hit)| self.hit(triangle, ray_origin, ray_direction, time, hit))
+    }
+}
+
+impl RayIntersect for Mesh {
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect {
+        self.trace(ray_origin, ray_direction, f32::INFINITY, time, false)
+            .unwrap_or_else(Intersect::empty)
+    }
+
+    /// Any hit will do for an opaque mesh; light passing through a transparent one
+    /// is tinted by the nearest surface, so that still takes the closest.
+    fn shadow_intersect(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+    ) -> Option<Intersect> {
+        let opaque = self.material.albedo[3] <= 0.0;
+        self.trace(ray_origin, ray_direction, max_distance, time, opaque)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn triangle(a: Vec3, b: Vec3, c: Vec3) -> Triangle {
+        Triangle {
+            positions: [a, b, c],
+            normals: None,
+            uvs: [Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)],
+        }
+    }
+
+    fn material() -> Material {
+        Material::new(Color::new(255, 255, 255), 10.0, [0.9, 0.1, 0.0, 0.0], 1.0, Color::black(), 0.0)
+    }
+
+    #[test]
+    fn moller_trumbore_finds_distance_and_barycentrics() {
+        let unit = triangle(Vec3::zeros(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let down = Vec3::new(0.0, 0.0, -1.0);
+
+        let (t, b1, b2) = unit.intersect(&Vec3::new(0.25, 0.5, 2.0), &down).unwrap();
+        assert!((t - 2.0).abs() < 1e-6);
+        assert!((b1 - 0.25).abs() < 1e-6 && (b2 - 0.5).abs() < 1e-6);
+
+        // Outside the triangle, behind the ray and parallel to the plane.
+        assert!(unit.intersect(&Vec3::new(0.75, 0.75, 2.0), &down).is_none());
+        assert!(unit.intersect(&Vec3::new(0.25, 0.25, -2.0), &down).is_none());
+        assert!(unit.intersect(&Vec3::new(0.25, 0.25, 1.0), &Vec3::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn mesh_hit_interpolates_texture_coordinates() {
+        let mesh = Mesh::new(
+            vec![triangle(Vec3::zeros(), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0))],
+            material(),
+        );
+        let hit = mesh.ray_intersect(&Vec3::new(0.5, 1.0, 3.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(hit.is_intersecting);
+        assert!((hit.distance - 3.0).abs() < 1e-5);
+        assert!((hit.u - 0.25).abs() < 1e-5 && (hit.v - 0.5).abs() < 1e-5);
+        assert!((hit.normal - Vec3::new(0.0, 0.0, 1.0)).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn bvh_finds_the_same_hits_as_testing_every_triangle() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let point = |rng: &mut StdRng, spread: f32| {
+            Vec3::new(
+                rng.gen_range(-spread..spread),
+                rng.gen_range(-spread..spread),
+                rng.gen_range(-spread..spread),
+            )
+        };
+        let triangles: Vec<Triangle> = (0..300)
+            .map(|_| {
+                let center = point(&mut rng, 10.0);
+                triangle(center, center + point(&mut rng, 1.5), center + point(&mut rng, 1.5))
+            })
+            .collect();
+        let mesh = Mesh::new(triangles.clone(), material());
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = point(&mut rng, 15.0);
+            let direction = (point(&mut rng, 8.0) - origin).normalize();
+            let brute_force = triangles
+                .iter()
+                .filter_map(|triangle| triangle.intersect(&origin, &direction))
+                .map(|(t, _, _)| t)
+                .fold(f32::INFINITY, f32::min);
+
+            let hit = mesh.ray_intersect(&origin, &direction, 0.0);
+            assert_eq!(hit.is_intersecting, brute_force.is_finite());
+            if hit.is_intersecting {
+                assert!((hit.distance - brute_force).abs() < 1e-4);
+                hits += 1;
+            }
+        }
+        assert!(hits > 100, "only {} rays hit anything", hits);
+    }
+
+    #[test]
+    fn shadow_rays_stop_at_any_opaque_hit_before_the_light() {
+        let wall = |z: f32| triangle(Vec3::new(-1.0, -1.0, z), Vec3::new(2.0, -1.0, z), Vec3::new(-1.0, 2.0, z));
+        let mesh = Mesh::new(vec![wall(0.0), wall(-2.0)], material());
+        let (origin, down) = (Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+
+        let hit = mesh.shadow_intersect(&origin, &down, 10.0, 0.0).unwrap();
+        assert!(hit.distance < 10.0);
+        assert!(mesh.shadow_intersect(&origin, &down, 2.5, 0.0).is_none());
+    }
+
+}
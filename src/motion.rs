@@ -0,0 +1,137 @@
+use nalgebra_glm::{Mat3, Mat4, Vec3, Vec4};
+use std::f32::consts::PI;
+
+use crate::ray_intersect::{Intersect, RayIntersect};
+use crate::transform::{object_ray, world_hit};
+
+/// Movement of an object or light over scene time, in seconds, as a transform
+/// applied on top of its rest placement. Every motion repeats, so an open window's
+/// ever-growing clock keeps objects near where they started.
+#[derive(Debug, Clone, Copy)]
+pub enum Motion {
+    /// Constant velocity in units per second from the rest position, starting over every `period` seconds.
+    Linear { velocity: Vec3, period: f32 },
+    /// Sways back and forth around the rest position, reaching `amplitude` every `period` seconds.
+    Oscillate { amplitude: Vec3, period: f32 },
+    /// Turns about `axis` through `pivot` at `speed` degrees per second.
+    Spin { axis: Vec3, speed: f32, pivot: Vec3 },
+    /// Grows from its rest size to `scale` times it and back every `period` seconds, about `pivot`.
+    Pulse { scale: f32, period: f32, pivot: Vec3 },
+}
+
+impl Motion {
+    /// The same motion turning or scaling about `pivot`; moves that only
+    /// translate are unchanged.
+    pub fn about(self, pivot: Vec3) -> Self {
+        match self {
+            Motion::Spin { axis, speed, .. } => Motion::Spin { axis, speed, pivot },
+            Motion::Pulse { scale, period, .. } => Motion::Pulse { scale, period, pivot },
+            motion => motion,
+        }
+    }
+
+    /// Where the motion has taken the rest placement at `time`.
+    pub fn transform(&self, time: f32) -> Mat4 {
+        match *self {
+            Motion::Linear { velocity, period } => {
+                if period <= 0.0 {
+                    return Mat4::identity();
+                }
+                nalgebra_glm::translation(&(velocity * time.rem_euclid(period)))
+            }
+            Motion::Oscillate { amplitude, period } => {
+                if period <= 0.0 {
+                    return Mat4::identity();
+                }
+                nalgebra_glm::translation(&(amplitude * (2.0 * PI * time / period).sin()))
+            }
+            Motion::Spin { axis, speed, pivot } => {
+                if axis.magnitude_squared() == 0.0 {
+                    return Mat4::identity();
+                }
+                let angle = (speed * time).rem_euclid(360.0).to_radians();
+                about_pivot(nalgebra_glm::rotation(angle, &axis), pivot)
+            }
+            Motion::Pulse { scale, period, pivot } => {
+                if period <= 0.0 || scale <= 0.0 {
+                    return Mat4::identity();
+                }
+                let amount = 0.5 - 0.5 * (2.0 * PI * time / period).cos();
+                let size = 1.0 + (scale - 1.0) * amount;
+                about_pivot(nalgebra_glm::scaling(&Vec3::new(size, size, size)), pivot)
+            }
+        }
+    }
+
+    pub fn point_at(&self, point: &Vec3, time: f32) -> Vec3 {
+        (self.transform(time) * Vec4::new(point.x, point.y, point.z, 1.0)).xyz()
+    }
+
+    /// A direction or extent at `time`: turned and scaled, but not moved.
+    pub fn vector_at(&self, vector: &Vec3, time: f32) -> Vec3 {
+        (self.transform(time) * Vec4::new(vector.x, vector.y, vector.z, 0.0)).xyz()
+    }
+}
+
+fn about_pivot(matrix: Mat4, pivot: Vec3) -> Mat4 {
+    nalgebra_glm::translation(&pivot) * matrix * nalgebra_glm::translation(&-pivot)
+}
+
+/// An object following a `Motion`. Rays are traced against it where it is at the
+/// ray's time, so rays spread over a shutter interval see it smeared along its path,
+/// whether it moves, turns or changes size.
+pub struct Moving<T: RayIntersect> {
+    pub object: T,
+    pub motion: Motion,
+}
+
+impl<T: RayIntersect> Moving<T> {
+    pub fn new(object: T, motion: Motion) -> Self {
+        Moving { object, motion }
+    }
+}
+
+impl<T: RayIntersect> Moving<T> {
+    /// The motion's transform at `time` and its inverse; `None` while it can't be inverted.
+    fn placement(&self, time: f32) -> Option<(Mat4, Mat4)> {
+        let matrix = self.motion.transform(time);
+        Some((matrix, matrix.try_inverse()?))
+    }
+
+    fn place_hit(&self, intersect: Intersect, ray_origin: &Vec3, ray_direction: &Vec3, matrix: &Mat4, inverse: &Mat4) -> Intersect {
+        let linear: Mat3 = matrix.fixed_view::<3, 3>(0, 0).into();
+        let normal_matrix: Mat3 = inverse.fixed_view::<3, 3>(0, 0).transpose();
+        world_hit(intersect, ray_origin, ray_direction, &linear, &normal_matrix)
+    }
+}
+
+impl<T: RayIntersect + 'static> RayIntersect for Moving<T> {
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect {
+        let Some((matrix, inverse)) = self.placement(time) else {
+            return Intersect::empty();
+        };
+        let (origin, direction) = object_ray(&inverse, ray_origin, ray_direction);
+        let intersect = self.object.ray_intersect(&origin, &direction, time);
+        if !intersect.is_intersecting {
+            return intersect;
+        }
+        self.place_hit(intersect, ray_origin, ray_direction, &matrix, &inverse)
+    }
+
+    fn shadow_intersect(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+    ) -> Option<Intersect> {
+        let (matrix, inverse) = self.placement(time)?;
+        let (origin, direction) = object_ray(&inverse, ray_origin, ray_direction);
+        let intersect = self.object.shadow_intersect(&origin, &direction, max_distance, time)?;
+        Some(self.place_hit(intersect, ray_origin, ray_direction, &matrix, &inverse))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
@@ -0,0 +1,330 @@
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+
+/// A value of Minecraft's Named Binary Tag format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// Field `key` of a compound.
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(fields) => fields.get(key),
+            _ => None,
+        }
+    }
+
+    /// Any integer tag, widened.
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(value) => Some(value as i64),
+            Tag::Short(value) => Some(value as i64),
+            Tag::Int(value) => Some(value as i64),
+            Tag::Long(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&HashMap<String, Tag>> {
+        match self {
+            Tag::Compound(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Tag::ByteArray(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a whole NBT document, gzip-compressed or not, returning the root tag's name and value.
+pub fn parse(data: &[u8]) -> Result<(String, Tag), String> {
+    let mut unpacked = Vec::new();
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(data)
+            .read_to_end(&mut unpacked)
+            .map_err(|e| format!("could not decompress: {}", e))?;
+        &unpacked[..]
+    } else {
+        data
+    };
+
+    let mut reader = Reader { data, pos: 0 };
+    let kind = reader.u8()?;
+    if kind != 10 {
+        return Err("root tag is not a compound".to_string());
+    }
+    let name = reader.string()?;
+    let root = reader.payload(kind, 0)?;
+    Ok((name, root))
+}
+
+/// Deeper nesting than any real file needs; stops crafted files from overflowing the stack.
+const MAX_DEPTH: usize = 512;
+
+/// Big-endian cursor over NBT data.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(count).filter(|&end| end <= self.data.len());
+        let end = end.ok_or("data ends in the middle of a tag")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        // Negative lengths appear in some writers' empty lists.
+        Ok(i32::from_be_bytes(self.array()?).max(0) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        // Java's modified UTF-8 only differs from UTF-8 for NUL and astral characters.
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+
+    fn payload(&mut self, kind: u8, depth: usize) -> Result<Tag, String> {
+        if depth > MAX_DEPTH {
+            return Err("tags nested too deeply".to_string());
+        }
+        Ok(match kind {
+            1 => Tag::Byte(i8::from_be_bytes(self.array()?)),
+            2 => Tag::Short(i16::from_be_bytes(self.array()?)),
+            3 => Tag::Int(i32::from_be_bytes(self.array()?)),
+            4 => Tag::Long(i64::from_be_bytes(self.array()?)),
+            5 => Tag::Float(f32::from_be_bytes(self.array()?)),
+            6 => Tag::Double(f64::from_be_bytes(self.array()?)),
+            7 => {
+                let length = self.length()?;
+                Tag::ByteArray(self.bytes(length)?.to_vec())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                let item_kind = self.u8()?;
+                let length = self.length()?;
+                let mut items = Vec::with_capacity(length.min(self.data.len()));
+                for _ in 0..length {
+                    items.push(self.payload(item_kind, depth + 1)?);
+                }
+                Tag::List(items)
+            }
+            10 => {
+                let mut fields = HashMap::new();
+                loop {
+                    let field_kind = self.u8()?;
+                    if field_kind == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    fields.insert(name, self.payload(field_kind, depth + 1)?);
+                }
+                Tag::Compound(fields)
+            }
+            11 => {
+                let length = self.length()?;
+                let bytes = self.bytes(length.checked_mul(4).ok_or("array too long")?)?;
+                Tag::IntArray(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            12 => {
+                let length = self.length()?;
+                let bytes = self.bytes(length.checked_mul(8).ok_or("array too long")?)?;
+                Tag::LongArray(
+                    bytes
+                        .chunks_exact(8)
+                        .map(|chunk| i64::from_be_bytes(chunk.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            _ => return Err(format!("unknown tag type {}", kind)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn kind(tag: &Tag) -> u8 {
+        match tag {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    fn write_string(out: &mut Vec<u8>, text: &str) {
+        out.extend((text.len() as u16).to_be_bytes());
+        out.extend(text.as_bytes());
+    }
+
+    /// The payload of `tag`, as the game writes it.
+    fn encode(tag: &Tag, out: &mut Vec<u8>) {
+        match tag {
+            Tag::Byte(value) => out.extend(value.to_be_bytes()),
+            Tag::Short(value) => out.extend(value.to_be_bytes()),
+            Tag::Int(value) => out.extend(value.to_be_bytes()),
+            Tag::Long(value) => out.extend(value.to_be_bytes()),
+            Tag::Float(value) => out.extend(value.to_be_bytes()),
+            Tag::Double(value) => out.extend(value.to_be_bytes()),
+            Tag::ByteArray(bytes) => {
+                out.extend((bytes.len() as i32).to_be_bytes());
+                out.extend(bytes);
+            }
+            Tag::String(text) => write_string(out, text),
+            Tag::List(items) => {
+                out.push(items.first().map_or(0, kind));
+                out.extend((items.len() as i32).to_be_bytes());
+                items.iter().for_each(|item| encode(item, out));
+            }
+            Tag::Compound(fields) => {
+                for (name, field) in fields {
+                    out.push(kind(field));
+                    write_string(out, name);
+                    encode(field, out);
+                }
+                out.push(0);
+            }
+            Tag::IntArray(values) => {
+                out.extend((values.len() as i32).to_be_bytes());
+                values.iter().for_each(|value| out.extend(value.to_be_bytes()));
+            }
+            Tag::LongArray(values) => {
+                out.extend((values.len() as i32).to_be_bytes());
+                values.iter().for_each(|value| out.extend(value.to_be_bytes()));
+            }
+        }
+    }
+
+    fn document(name: &str, root: &Tag) -> Vec<u8> {
+        let mut out = vec![kind(root)];
+        write_string(&mut out, name);
+        encode(root, &mut out);
+        out
+    }
+
+    fn compound(fields: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(fields.into_iter().map(|(name, tag)| (name.to_string(), tag)).collect())
+    }
+
+    fn every_tag() -> Tag {
+        compound(vec![
+            ("byte", Tag::Byte(-7)),
+            ("short", Tag::Short(-300)),
+            ("int", Tag::Int(70_000)),
+            ("long", Tag::Long(-5_000_000_000)),
+            ("float", Tag::Float(1.5)),
+            ("double", Tag::Double(-0.25)),
+            ("bytes", Tag::ByteArray(vec![0, 1, 255])),
+            ("string", Tag::String("minecraft:snow_block".to_string())),
+            ("list", Tag::List(vec![Tag::Short(1), Tag::Short(2)])),
+            ("empty", Tag::List(Vec::new())),
+            ("nested", compound(vec![("inner", Tag::String(String::new()))])),
+            ("ints", Tag::IntArray(vec![i32::MIN, 0, i32::MAX])),
+            ("longs", Tag::LongArray(vec![i64::MIN, i64::MAX])),
+        ])
+    }
+
+    #[test]
+    fn round_trips_every_tag_type() {
+        let root = every_tag();
+        let (name, parsed) = parse(&document("Schematic", &root)).unwrap();
+        assert_eq!(name, "Schematic");
+        assert_eq!(parsed, root);
+    }
+
+    #[test]
+    fn reads_gzip_compressed_documents() {
+        let root = every_tag();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&document("", &root)).unwrap();
+        let packed = encoder.finish().unwrap();
+        assert_eq!(&packed[..2], &[0x1f, 0x8b]);
+
+        assert_eq!(parse(&packed).unwrap().1, root);
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| (0..depth).fold(Tag::Int(1), |tag, _| Tag::List(vec![tag]));
+        assert!(parse(&document("", &compound(vec![("deep", nested(MAX_DEPTH - 1))]))).is_ok());
+        let error = parse(&document("", &compound(vec![("deep", nested(MAX_DEPTH + 1))]))).unwrap_err();
+        assert!(error.contains("nested too deeply"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        let data = document("", &every_tag());
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        assert!(parse(&document("", &Tag::Int(3))).is_err());
+
+        let mut unknown = document("", &compound(vec![("byte", Tag::Byte(1))]));
+        unknown[3] = 13;
+        assert!(parse(&unknown).unwrap_err().contains("unknown tag type"));
+
+        // An array length that would overflow when turned into a byte count.
+        let mut huge = vec![10, 0, 0, 12, 0, 1, b'a'];
+        huge.extend(i32::MAX.to_be_bytes());
+        assert!(parse(&huge).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use nalgebra_glm::Vec3;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::color::Color;
+
+/// Improved Perlin noise over a seeded permutation table.
+#[derive(Clone)]
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let mut perm = [0u8; 512];
+        for (i, value) in perm.iter_mut().enumerate() {
+            *value = table[i & 255];
+        }
+        Perlin { perm }
+    }
+
+    fn hash(&self, x: i32, y: i32, z: i32) -> u8 {
+        let x = (x & 255) as usize;
+        let y = (y & 255) as usize;
+        let z = (z & 255) as usize;
+        self.perm[self.perm[self.perm[x] as usize + y] as usize + z]
+    }
+
+    /// Gradient noise in roughly `[-1, 1]`.
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let cell = Vec3::new(p.x.floor(), p.y.floor(), p.z.floor());
+        let f = p - cell;
+        let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+        let grad = |hash: u8, dx: f32, dy: f32, dz: f32| {
+            let h = hash & 15;
+            let a = if h < 8 { dx } else { dy };
+            let b = if h < 4 {
+                dy
+            } else if h == 12 || h == 14 {
+                dx
+            } else {
+                dz
+            };
+            (if h & 1 == 0 { a } else { -a }) + (if h & 2 == 0 { b } else { -b })
+        };
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+
+        let corner = |ox: i32, oy: i32, oz: i32| {
+            grad(
+                self.hash(x + ox, y + oy, z + oz),
+                f.x - ox as f32,
+                f.y - oy as f32,
+                f.z - oz as f32,
+            )
+        };
+
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+                lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+            ),
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+                lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+            ),
+        )
+    }
+
+    /// Fractal Brownian motion: `octaves` layers of noise, each `lacunarity` times
+    /// finer and `gain` times weaker than the last. Normalized to roughly `[-1, 1]`.
+    pub fn fbm(&self, p: &Vec3, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total_amplitude = 0.0;
+        let mut point = *p;
+
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * self.noise(&point);
+            total_amplitude += amplitude;
+            amplitude *= gain;
+            point *= lacunarity;
+        }
+
+        sum / total_amplitude
+    }
+
+    /// Sum of absolute noise octaves, in `[0, 1]`, for marble veins and wood grain.
+    pub fn turbulence(&self, p: &Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total_amplitude = 0.0;
+        let mut point = *p;
+
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * self.noise(&point).abs();
+            total_amplitude += amplitude;
+            amplitude *= 0.5;
+            point *= 2.0;
+        }
+
+        sum / total_amplitude
+    }
+
+    /// Worley (cellular) noise: distance to the closest of one random feature point per cell.
+    pub fn worley(&self, p: &Vec3) -> f32 {
+        let cell = Vec3::new(p.x.floor(), p.y.floor(), p.z.floor());
+        let mut closest = f32::INFINITY;
+
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (x, y, z) = (cell.x as i32 + dx, cell.y as i32 + dy, cell.z as i32 + dz);
+                    let feature = Vec3::new(
+                        x as f32 + self.hash(x, y, z) as f32 / 255.0,
+                        y as f32 + self.hash(x + 31, y, z) as f32 / 255.0,
+                        z as f32 + self.hash(x, y + 57, z) as f32 / 255.0,
+                    );
+                    closest = closest.min((feature - p).magnitude());
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+/// Coordinates a procedural texture is evaluated in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureSpace {
+    /// `(u, v, 0)`, following the surface parameterization.
+    Uv,
+    /// Position relative to the object, so the pattern moves with it.
+    Object,
+    World,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Pattern {
+    Noise {
+        octaves: u32,
+        lacunarity: f32,
+        gain: f32,
+    },
+    Worley,
+    Checker,
+    /// Alternating bands across `direction`.
+    Stripes {
+        direction: Vec3,
+    },
+    Marble {
+        turbulence: f32,
+        octaves: u32,
+    },
+    /// Concentric rings around the Y axis.
+    Wood {
+        turbulence: f32,
+    },
+}
+
+/// A texture computed from a 3D pattern instead of an image. The pattern gives a
+/// value in `[0, 1]` that blends `color_a` into `color_b`; as a normal source the
+/// same value is treated as a height field and `bump_strength` scales its slope.
+#[derive(Clone)]
+pub struct ProceduralTexture {
+    pub pattern: Pattern,
+    pub space: TextureSpace,
+    pub frequency: f32,
+    pub color_a: Color,
+    pub color_b: Color,
+    pub bump_strength: f32,
+    perlin: Perlin,
+}
+
+impl ProceduralTexture {
+    pub fn new(pattern: Pattern, space: TextureSpace, frequency: f32, seed: u64) -> Self {
+        ProceduralTexture {
+            pattern,
+            space,
+            frequency,
+            color_a: Color::black(),
+            color_b: Color::new(255, 255, 255),
+            bump_strength: 1.0,
+            perlin: Perlin::new(seed),
+        }
+    }
+
+    pub fn with_colors(mut self, color_a: Color, color_b: Color) -> Self {
+        self.color_a = color_a;
+        self.color_b = color_b;
+        self
+    }
+
+    pub fn with_bump_strength(mut self, bump_strength: f32) -> Self {
+        self.bump_strength = bump_strength;
+        self
+    }
+
+    /// Pattern value in `[0, 1]` at `p`, already expressed in `self.space`.
+    pub fn value(&self, p: &Vec3) -> f32 {
+        let p = p * self.frequency;
+
+        let value = match self.pattern {
+            Pattern::Noise {
+                octaves,
+                lacunarity,
+                gain,
+            } => self.perlin.fbm(&p, octaves, lacunarity, gain) * 0.5 + 0.5,
+            Pattern::Worley => self.perlin.worley(&p),
+            Pattern::Checker => {
+                let sum = p.x.floor() + p.y.floor() + p.z.floor();
+                sum.rem_euclid(2.0)
+            }
+            Pattern::Stripes { direction } => p.dot(&direction.normalize()).floor().rem_euclid(2.0),
+            Pattern::Marble { turbulence, octaves } => {
+                let phase = p.x + turbulence * self.perlin.turbulence(&p, octaves);
+                0.5 + 0.5 * phase.sin()
+            }
+            Pattern::Wood { turbulence } => {
+                let radius = (p.x * p.x + p.z * p.z).sqrt();
+                (radius + turbulence * self.perlin.noise(&p)).rem_euclid(1.0)
+            }
+        };
+
+        value.clamp(0.0, 1.0)
+    }
+
+    pub fn color(&self, p: &Vec3) -> Color {
+        let t = self.value(p);
+        self.color_a * (1.0 - t) + self.color_b * t
+    }
+
+    /// Gradient of the pattern value at `p`, by central differences.
+    pub fn gradient(&self, p: &Vec3) -> Vec3 {
+        let h = 1e-3 / self.frequency.max(1e-3);
+        let dx = Vec3::new(h, 0.0, 0.0);
+        let dy = Vec3::new(0.0, h, 0.0);
+        let dz = Vec3::new(0.0, 0.0, h);
+
+        Vec3::new(
+            self.value(&(p + dx)) - self.value(&(p - dx)),
+            self.value(&(p + dy)) - self.value(&(p - dy)),
+            self.value(&(p + dz)) - self.value(&(p - dz)),
+        ) / (2.0 * h)
+    }
+}
+
+impl std::fmt::Debug for ProceduralTexture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProceduralTexture")
+            .field("pattern", &self.pattern)
+            .field("space", &self.space)
+            .field("frequency", &self.frequency)
+            .finish()
+    }
+}
@@ -1,5 +1,30 @@
-use nalgebra_glm::Vec3;
+use nalgebra_glm::{Mat3, Vec2, Vec3};
 use crate::material::Material;
+use crate::procedural::TextureSpace;
+use crate::texture::UvDerivatives;
+
+/// Ray cone used as a cheap ray differential: the footprint of a pixel grows
+/// from `width` by `spread` (radians) per unit of distance travelled.
+#[derive(Debug, Clone, Copy)]
+pub struct RayCone {
+    pub width: f32,
+    pub spread: f32,
+}
+
+impl RayCone {
+    pub fn new(width: f32, spread: f32) -> Self {
+        RayCone { width, spread }
+    }
+
+    pub fn width_at(&self, distance: f32) -> f32 {
+        self.width + self.spread * distance
+    }
+
+    /// Cone continuing from a hit at `distance`, e.g. for a reflected or refracted ray.
+    pub fn propagate(&self, distance: f32) -> RayCone {
+        RayCone::new(self.width_at(distance), self.spread)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Intersect {
@@ -10,6 +35,16 @@ pub struct Intersect {
     pub material: Material,
     pub u: f32,
     pub v: f32,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub uv_derivatives: UvDerivatives,
+    /// Hit point relative to the object, for patterns that should move with it.
+    pub object_point: Vec3,
+    /// Time of the ray that made the hit, in seconds of scene time; drives animated textures.
+    pub time: f32,
+    /// `(u, v, dpdu, dpdv)` from before a world-space uv transform, so the transform
+    /// can be applied again once the hit is placed in the world at its real size.
+    surface: Option<(f32, f32, Vec3, Vec3)>,
 }
 
 impl Intersect {
@@ -22,9 +57,122 @@ impl Intersect {
             material,
             u,
             v,
+            dpdu: Vec3::new(0.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, 0.0),
+            uv_derivatives: UvDerivatives::default(),
+            object_point: point,
+            time: 0.0,
+            surface: None,
+        }
+    }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_object_point(mut self, object_point: Vec3) -> Self {
+        self.object_point = object_point;
+        self
+    }
+
+    /// Coordinates for evaluating a procedural texture in `space`.
+    pub fn texture_point(&self, space: TextureSpace) -> Vec3 {
+        match space {
+            TextureSpace::Uv => Vec3::new(self.u, self.v, 0.0),
+            TextureSpace::Object => self.object_point,
+            TextureSpace::World => self.point,
         }
     }
 
+    /// Sets the world-space change of the hit point per unit of `u` and `v`.
+    pub fn with_surface_derivatives(mut self, dpdu: Vec3, dpdv: Vec3) -> Self {
+        self.dpdu = dpdu;
+        self.dpdv = dpdv;
+        self
+    }
+
+    /// Applies the material's uv transform to `(u, v)`, keeping `dpdu`/`dpdv` in
+    /// step so footprints and tangent frames follow the transformed coordinates.
+    pub fn apply_uv_transform(mut self) -> Self {
+        self.transform_uv();
+        self
+    }
+
+    fn transform_uv(&mut self) {
+        let transform = self.material.uv_transform;
+        if transform.is_identity() {
+            return;
+        }
+
+        if transform.world_space {
+            self.surface = Some((self.u, self.v, self.dpdu, self.dpdv));
+        }
+        let face_size = Vec2::new(self.dpdu.magnitude(), self.dpdv.magnitude());
+        let matrix = transform.matrix(face_size);
+        let uv = matrix * Vec2::new(self.u, self.v) + transform.offset;
+        self.u = uv.x;
+        self.v = uv.y;
+
+        if let Some(inverse) = matrix.try_inverse() {
+            let (dpdu, dpdv) = (self.dpdu, self.dpdv);
+            self.dpdu = dpdu * inverse[(0, 0)] + dpdv * inverse[(1, 0)];
+            self.dpdv = dpdu * inverse[(0, 1)] + dpdv * inverse[(1, 1)];
+        }
+    }
+
+    /// Carries `dpdu`/`dpdv` through `linear`, e.g. from an object's space into the
+    /// world's. A world-space uv transform is redone at the face's size after `linear`,
+    /// so tiling stays in world units on scaled instances. Alpha tests made inside
+    /// the object still see the face at its object size.
+    pub fn transform_surface(&mut self, linear: &Mat3) {
+        match self.surface.take() {
+            Some((u, v, dpdu, dpdv)) => {
+                self.u = u;
+                self.v = v;
+                self.dpdu = linear * dpdu;
+                self.dpdv = linear * dpdv;
+                self.transform_uv();
+            }
+            None => {
+                self.dpdu = linear * self.dpdu;
+                self.dpdv = linear * self.dpdv;
+            }
+        }
+    }
+
+    /// Projects a ray cone of the given `width` onto the surface and stores the
+    /// resulting texture-space footprint in `uv_derivatives`.
+    pub fn compute_uv_derivatives(&mut self, ray_direction: &Vec3, width: f32) {
+        let dpdu_len2 = self.dpdu.magnitude_squared();
+        let dpdv_len2 = self.dpdv.magnitude_squared();
+        if dpdu_len2 == 0.0 || dpdv_len2 == 0.0 || width <= 0.0 {
+            self.uv_derivatives = UvDerivatives::default();
+            return;
+        }
+
+        let cos_theta = ray_direction.normalize().dot(&self.normal).abs().max(0.05);
+        let minor_dir = {
+            let axis = ray_direction.cross(&self.normal);
+            if axis.magnitude_squared() > 1e-8 {
+                axis.normalize()
+            } else {
+                self.dpdu.normalize()
+            }
+        };
+        let major_dir = self.normal.cross(&minor_dir).normalize();
+
+        let minor = minor_dir * width;
+        let major = major_dir * (width / cos_theta);
+
+        self.uv_derivatives = UvDerivatives {
+            dudx: minor.dot(&self.dpdu) / dpdu_len2,
+            dvdx: minor.dot(&self.dpdv) / dpdv_len2,
+            dudy: major.dot(&self.dpdu) / dpdu_len2,
+            dvdy: major.dot(&self.dpdv) / dpdv_len2,
+        };
+    }
+
     pub fn empty() -> Self {
         Intersect {
             point: Vec3::new(0.0, 0.0, 0.0),
@@ -34,12 +182,34 @@ impl Intersect {
             material: Material::black(),
             u: 0.0,
             v: 0.0,
+            dpdu: Vec3::new(0.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, 0.0),
+            uv_derivatives: UvDerivatives::default(),
+            object_point: Vec3::new(0.0, 0.0, 0.0),
+            time: 0.0,
+            surface: None,
         }
     }
 }
 
 pub trait RayIntersect: Send + Sync {
-    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3) -> Intersect;
+    /// Closest hit along the ray. `time` is when the ray was cast, in seconds of scene
+    /// time, so moving objects can be tested where they are at that moment.
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect;
+
+    /// A hit closer than `max_distance`, for shadow rays. It needn't be the closest,
+    /// so objects can stop at the first opaque surface they find.
+    fn shadow_intersect(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+    ) -> Option<Intersect> {
+        let intersect = self.ray_intersect(ray_origin, ray_direction, time);
+        (intersect.is_intersecting && intersect.distance < max_distance).then_some(intersect)
+    }
+
     fn as_any(&self) -> &dyn std::any::Any;
 }
 
@@ -0,0 +1,575 @@
+use nalgebra_glm::{Mat4, Vec3};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::animation::{CameraKeyframe, CameraPath, Easing, IntensityCurve, Interpolation};
+use crate::atlas::TextureAtlas;
+use crate::color::Color;
+use crate::gltf_import::{self, ImportedCamera};
+use crate::material::Material;
+use crate::motion::Motion;
+use crate::schematic::{Schematic, DEFAULT_BLOCK_MATERIALS};
+use crate::scene_graph::SceneNode;
+use crate::structure::{self, Dome, Structure};
+use crate::terrain::Terrain;
+use crate::texture::{Texture, TextureAnimation, TextureSource};
+use crate::vox::VoxFile;
+
+/// Scene description loaded from a TOML file:
+///
+/// ```toml
+/// seed = 42
+///
+/// [render]
+/// width = 1280
+/// height = 720
+/// fps = 24
+/// shutter = 0.02
+///
+/// [camera]
+/// interpolation = "catmull_rom"
+///
+/// [[camera.keyframes]]
+/// time = 0.0
+/// position = [0.0, 15.0, 30.0]
+/// target = [0.0, 0.0, 0.0]
+/// fov = 60.0
+///
+/// [nodes.igloo]
+/// position = [0.0, 0.0, -4.0]
+/// rotation = [0.0, 45.0, 0.0]
+///
+/// [nodes.platform]
+/// visible = false
+///
+/// [nodes.watchtower]
+/// motion = { type = "oscillate", amplitude = [0.0, 0.5, 0.0], period = 4.0 }
+///
+/// [nodes.sled]
+/// motion = { type = "spin", axis = [0.0, 1.0, 0.0], speed = 90.0 }
+///
+/// [terrain]
+/// type = "noise"
+/// size = 21
+/// height = 5.0
+/// scale = 0.15
+/// octaves = 4
+/// clearing = 5.0
+///
+/// [igloo]
+/// radius = 4.5
+/// door = "east"
+///
+/// [[structures]]
+/// name = "watchtower"
+/// materials = ["stone", "snow"]
+/// shape = { type = "tower", center = [7, -7], radius = 1.5, height = 5 }
+///
+/// [[models]]
+/// name = "sled"
+/// path = "models/sled.vox"
+/// position = [5, 0, 6]
+/// scale = 0.25
+///
+/// [atlas]
+/// image = "assets/atlas.png"
+/// tiles = "assets/atlas.toml"
+///
+/// [materials.glowstone]
+/// emission_curve = { flicker = { speed = 3.0, amount = 0.3 } }
+///
+/// [materials.lava]
+/// texture = "assets/lava_still.png"
+/// animation = { frame_time = 0.1, interpolate = true }
+/// emission = 0.3
+/// emission_color = [255, 120, 20]
+///
+/// [blocks]
+/// "spruce_planks" = "assets/spruce_planks.png"
+/// "white_concrete" = "snow"
+/// "blue_ice" = "atlas_blue_ice"
+/// "lava" = "lava"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneFile {
+    /// Seed for everything random in the scene; a fresh one is picked when unset.
+    pub seed: Option<u64>,
+    pub render: RenderSettings,
+    pub camera: CameraSettings,
+    /// Changes to named scene graph nodes.
+    pub nodes: HashMap<String, NodeSettings>,
+    pub terrain: Terrain,
+    /// Shape of the generated igloo.
+    pub igloo: Dome,
+    /// Extra generated structures, each added as a node of its own.
+    pub structures: Vec<StructureSettings>,
+    /// Models loaded from files, each added as a node of its own.
+    pub models: Vec<ModelSettings>,
+    /// A texture atlas whose tiles become materials of the same name.
+    pub atlas: Option<AtlasSettings>,
+    /// Changes to named materials, or new materials made from a texture.
+    pub materials: HashMap<String, MaterialSettings>,
+    /// Materials for Minecraft block names in imported builds: a scene material's
+    /// name or the path of a texture image. Adds to and overrides `DEFAULT_BLOCK_MATERIALS`.
+    pub blocks: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    /// Frames per second of rendered sequences; frame `n` shows time `n / fps`.
+    pub fps: f32,
+    /// Seconds the shutter stays open per frame for motion blur; 0 disables it.
+    /// Half the frame time (`0.5 / fps`) is the classic 180° film shutter.
+    pub shutter: f32,
+    /// Rays per pixel spread over the shutter interval.
+    pub motion_samples: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 800,
+            height: 600,
+            fps: 24.0,
+            shutter: 0.0,
+            motion_samples: 8,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraSettings {
+    pub interpolation: Interpolation,
+    pub keyframes: Vec<KeyframeSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyframeSettings {
+    pub time: f32,
+    pub position: [f32; 3],
+    pub target: [f32; 3],
+    /// Vertical field of view in degrees.
+    pub fov: Option<f32>,
+    pub focus_distance: Option<f32>,
+    pub in_handle: Option<[f32; 3]>,
+    pub out_handle: Option<[f32; 3]>,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+/// Applied on top of a node's own placement: scaled, then rotated, then moved.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSettings {
+    pub position: Option<[f32; 3]>,
+    /// Degrees about X, then Y, then Z.
+    pub rotation: Option<[f32; 3]>,
+    pub scale: Option<f32>,
+    pub visible: Option<bool>,
+    /// Name of a material to use for every block of the node.
+    pub material: Option<String>,
+    /// Keeps the node moving, e.g. `{ type = "oscillate", amplitude = [0.0, 0.6, 0.0], period = 2.0 }`,
+    /// `{ type = "linear", velocity = [1.0, 0.0, 0.0], period = 4.0 }`, `{ type = "spin", axis = [0.0, 1.0, 0.0],
+    /// speed = 90.0 }` or `{ type = "pulse", scale = 1.2, period = 1.0 }`.
+    pub motion: Option<MotionSettings>,
+}
+
+/// A node's `Motion`, in blocks, degrees and seconds. Spins and pulses are about the node's origin.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MotionSettings {
+    Linear { velocity: [f32; 3], period: f32 },
+    Oscillate { amplitude: [f32; 3], period: f32 },
+    Spin { axis: [f32; 3], speed: f32 },
+    Pulse { scale: f32, period: f32 },
+}
+
+impl MotionSettings {
+    fn is_valid(&self) -> bool {
+        match *self {
+            MotionSettings::Linear { period, .. } | MotionSettings::Oscillate { period, .. } => period > 0.0,
+            MotionSettings::Spin { axis, .. } => axis != [0.0; 3],
+            MotionSettings::Pulse { scale, period } => scale > 0.0 && period > 0.0,
+        }
+    }
+}
+
+impl From<MotionSettings> for Motion {
+    fn from(settings: MotionSettings) -> Self {
+        match settings {
+            MotionSettings::Linear { velocity, period } => Motion::Linear {
+                velocity: Vec3::from(velocity),
+                period,
+            },
+            MotionSettings::Oscillate { amplitude, period } => Motion::Oscillate {
+                amplitude: Vec3::from(amplitude),
+                period,
+            },
+            MotionSettings::Spin { axis, speed } => Motion::Spin {
+                axis: Vec3::from(axis),
+                speed,
+                pivot: Vec3::zeros(),
+            },
+            MotionSettings::Pulse { scale, period } => Motion::Pulse {
+                scale,
+                period,
+                pivot: Vec3::zeros(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructureSettings {
+    pub name: String,
+    /// The two alternating materials, by name. Defaults to packed ice and ice.
+    pub materials: Option<[String; 2]>,
+    pub shape: Structure,
+}
+
+/// Changes to a material, by name. A name that isn't a material yet makes a new
+/// one from `texture`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialSettings {
+    /// Image file to texture the material with.
+    pub texture: Option<String>,
+    /// Plays the texture as a vertical strip of square frames.
+    pub animation: Option<TextureAnimation>,
+    /// Glow strength; glowstone glows at 0.3.
+    pub emission: Option<f32>,
+    pub emission_color: Option<[u8; 3]>,
+    /// Varies the glow over time, e.g. a flicker.
+    pub emission_curve: Option<IntensityCurve>,
+}
+
+/// An atlas image and the JSON or TOML file naming its tiles.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AtlasSettings {
+    pub image: String,
+    pub tiles: String,
+}
+
+/// A model file placed in the scene. The format follows the extension: `.vox` for
+/// MagicaVoxel, `.schem`, `.schematic` or `.nbt` for Minecraft builds, `.gltf` or
+/// `.glb` for glTF 2.0 scenes, where one glTF unit is one block.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelSettings {
+    pub name: String,
+    pub path: String,
+    /// Cell the model's footprint is centered on.
+    #[serde(default)]
+    pub position: [i32; 3],
+    /// Size of one voxel (or glTF unit) relative to a scene block.
+    #[serde(default = "default_model_scale")]
+    pub scale: f32,
+}
+
+fn default_model_scale() -> f32 {
+    1.0
+}
+
+impl NodeSettings {
+    fn transform(&self) -> Mat4 {
+        let [x, y, z] = self.position.unwrap_or_default();
+        let [rx, ry, rz] = self.rotation.unwrap_or_default().map(f32::to_radians);
+        let scale = self.scale.unwrap_or(1.0);
+        nalgebra_glm::translation(&Vec3::new(x, y, z))
+            * nalgebra_glm::rotation(rz, &Vec3::z())
+            * nalgebra_glm::rotation(ry, &Vec3::y())
+            * nalgebra_glm::rotation(rx, &Vec3::x())
+            * nalgebra_glm::scaling(&Vec3::new(scale, scale, scale))
+    }
+}
+
+impl SceneFile {
+    pub fn load(path: &str) -> Result<SceneFile, String> {
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("could not read scene {}: {}", path, e))?;
+        let scene: SceneFile = toml::from_str(&source).map_err(|e| format!("{}: {}", path, e))?;
+
+        if scene.render.width == 0 || scene.render.height == 0 || scene.render.fps <= 0.0 {
+            return Err(format!("{}: render size and fps must be positive", path));
+        }
+        if scene.render.shutter < 0.0 {
+            return Err(format!("{}: shutter must not be negative", path));
+        }
+        if scene.nodes.values().any(|node| node.scale.is_some_and(|scale| scale <= 0.0)) {
+            return Err(format!("{}: node scale must be positive", path));
+        }
+        if scene.nodes.values().any(|node| node.motion.is_some_and(|motion| !motion.is_valid())) {
+            return Err(format!(
+                "{}: node motion periods and pulse scales must be positive, and spin axes non-zero",
+                path
+            ));
+        }
+        if scene.models.iter().any(|model| model.scale <= 0.0) {
+            return Err(format!("{}: model scale must be positive", path));
+        }
+        Ok(scene)
+    }
+
+    pub fn camera_path(&self) -> Option<CameraPath> {
+        if self.camera.keyframes.is_empty() {
+            return None;
+        }
+
+        let vec3 = |v: [f32; 3]| Vec3::new(v[0], v[1], v[2]);
+        let keyframes = self
+            .camera
+            .keyframes
+            .iter()
+            .map(|key| CameraKeyframe {
+                time: key.time,
+                position: vec3(key.position),
+                target: vec3(key.target),
+                fov: key.fov.map(f32::to_radians),
+                focus_distance: key.focus_distance,
+                in_handle: key.in_handle.map(vec3),
+                out_handle: key.out_handle.map(vec3),
+                easing: key.easing,
+            })
+            .collect();
+        Some(CameraPath::new(keyframes, self.camera.interpolation))
+    }
+
+    /// A node of blocks for each of the scene's `[[structures]]`, with cell `[0, 0, 0]`
+    /// centered over `origin`.
+    pub fn structure_nodes(
+        &self,
+        origin: Vec3,
+        block_size: f32,
+        materials: &HashMap<&str, Material>,
+    ) -> Result<Vec<SceneNode>, String> {
+        self.structures
+            .iter()
+            .map(|settings| {
+                let [even, odd] = match &settings.materials {
+                    Some([even, odd]) => [even.as_str(), odd.as_str()],
+                    None => ["packed_ice", "ice"],
+                }
+                .map(|name| {
+                    materials
+                        .get(name)
+                        .ok_or_else(|| format!("structure '{}': unknown material '{}'", settings.name, name))
+                });
+
+                let mut node = SceneNode::new(&settings.name);
+                for cube in structure::blocks(&settings.shape.cells(), origin, block_size, even?, odd?) {
+                    node.add_block(cube);
+                }
+                Ok(node)
+            })
+            .collect()
+    }
+
+    /// A material for each tile of the scene's `[atlas]`, named after the tile.
+    pub fn atlas_materials(&self) -> Result<HashMap<String, Material>, String> {
+        let Some(settings) = &self.atlas else {
+            return Ok(HashMap::new());
+        };
+        let atlas = TextureAtlas::new(&settings.image, &settings.tiles)?;
+        let mut materials = HashMap::new();
+        for name in atlas.tile_names() {
+            let material = Material::new(Color::black(), 2.0, [0.8, 0.1, 0.0, 0.0], 0.0, Color::black(), 0.0)
+                .with_atlas_tile(&atlas, name)?;
+            materials.insert(name.to_string(), material);
+        }
+        Ok(materials)
+    }
+
+    /// Applies the `[materials.<name>]` tables to `materials`, adding the new ones.
+    pub fn apply_materials<'a>(&'a self, materials: &mut HashMap<&'a str, Material>) -> Result<(), String> {
+        for (name, settings) in &self.materials {
+            let mut material = match (materials.get(name.as_str()), &settings.texture) {
+                (existing, Some(path)) => {
+                    let texture = Texture::load(path).map_err(|e| format!("material '{}': {}", name, e))?;
+                    let mut material = existing.cloned().unwrap_or_else(|| {
+                        Material::new(Color::black(), 2.0, [0.8, 0.1, 0.0, 0.0], 0.0, Color::black(), 0.0)
+                    });
+                    material.texture = Some(TextureSource::Image(Arc::new(texture)));
+                    material.has_texture = true;
+                    material.atlas_tile = None;
+                    material
+                }
+                (Some(material), None) => material.clone(),
+                (None, None) => {
+                    return Err(format!(
+                        "material '{}': no material of that name, and no texture to make one from",
+                        name
+                    ))
+                }
+            };
+
+            if let Some(animation) = &settings.animation {
+                let Some(TextureSource::Image(texture)) = &material.texture else {
+                    return Err(format!("material '{}': only image textures can be animated", name));
+                };
+                let animated = (**texture).clone().with_animation(animation.clone());
+                material.texture = Some(TextureSource::Image(Arc::new(animated)));
+            }
+            if let Some(emission) = settings.emission {
+                material.emission_intensity = emission;
+            }
+            if let Some([r, g, b]) = settings.emission_color {
+                material.emission_color = Color::new(r, g, b);
+            }
+            if let Some(curve) = &settings.emission_curve {
+                material = material.with_emission_curve(curve.clone());
+            }
+            materials.insert(name, material);
+        }
+        Ok(())
+    }
+
+    /// A node for each of the scene's `[[models]]`, positioned on the grid whose
+    /// cell `[0, 0, 0]` stands centered on `origin`, along with any cameras the models define.
+    pub fn model_nodes(
+        &self,
+        origin: Vec3,
+        block_size: f32,
+        materials: &HashMap<&str, Material>,
+    ) -> Result<(Vec<SceneNode>, Vec<ImportedCamera>), String> {
+        let block_materials = self.block_materials(materials)?;
+        // Bright magenta so blocks missing from the table stand out.
+        let placeholder = Material::new(
+            Color::new(255, 0, 255),
+            10.0,
+            [0.9, 0.1, 0.0, 0.0],
+            1.0,
+            Color::black(),
+            0.0,
+        );
+
+        let mut nodes = Vec::new();
+        let mut cameras = Vec::new();
+        for settings in &self.models {
+            let [x, y, z] = settings.position;
+            let model_origin = origin + Vec3::new(x as f32, y as f32, z as f32) * block_size;
+            let voxel_size = block_size * settings.scale;
+
+            let extension = Path::new(&settings.path)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(str::to_ascii_lowercase);
+            let blocks = match extension.as_deref() {
+                Some("gltf" | "glb") => {
+                    let placement = nalgebra_glm::translation(&model_origin)
+                        * nalgebra_glm::scaling(&Vec3::new(voxel_size, voxel_size, voxel_size));
+                    let imported = gltf_import::load(&settings.path, &settings.name, placement)?;
+                    nodes.push(imported.node);
+                    cameras.extend(imported.cameras);
+                    continue;
+                }
+                Some("vox") => VoxFile::load(&settings.path)?.blocks(model_origin, voxel_size),
+                Some("schem" | "schematic" | "nbt") => {
+                    let schematic = Schematic::load(&settings.path)?;
+                    let (cubes, unknown) =
+                        schematic.cubes(model_origin, voxel_size, &block_materials, &placeholder);
+                    if !unknown.is_empty() {
+                        let list: Vec<String> =
+                            unknown.iter().map(|(name, count)| format!("{} ({})", name, count)).collect();
+                        eprintln!(
+                            "warning: {}: no material for {}; shown in magenta. Add them under [blocks].",
+                            settings.path,
+                            list.join(", ")
+                        );
+                    }
+                    cubes
+                }
+                _ => {
+                    return Err(format!(
+                        "model '{}': unsupported file type {}",
+                        settings.name, settings.path
+                    ))
+                }
+            };
+
+            let mut node = SceneNode::new(&settings.name);
+            for cube in blocks {
+                node.add_block(cube);
+            }
+            nodes.push(node);
+        }
+        Ok((nodes, cameras))
+    }
+
+    /// The block name to material table for imported builds.
+    fn block_materials(&self, materials: &HashMap<&str, Material>) -> Result<HashMap<String, Material>, String> {
+        let mut table = HashMap::new();
+        for (block, material) in DEFAULT_BLOCK_MATERIALS {
+            if let Some(material) = materials.get(material) {
+                table.insert(block.to_string(), material.clone());
+            }
+        }
+
+        let mut textures: HashMap<&str, Material> = HashMap::new();
+        for (block, source) in &self.blocks {
+            let material = match materials.get(source.as_str()) {
+                Some(material) => material.clone(),
+                None if Path::new(source).is_file() => match textures.get(source.as_str()) {
+                    Some(material) => material.clone(),
+                    None => {
+                        let texture = Texture::load(source).map_err(|e| format!("block '{}': {}", block, e))?;
+                        let material = Material::new_with_texture(
+                            2.0,
+                            [0.8, 0.1, 0.0, 0.0],
+                            0.0,
+                            Arc::new(texture),
+                            None,
+                            Color::black(),
+                            0.0,
+                        );
+                        textures.insert(source, material.clone());
+                        material
+                    }
+                },
+                None => {
+                    return Err(format!(
+                        "block '{}': '{}' is neither a material nor an image file",
+                        block, source
+                    ))
+                }
+            };
+            table.insert(block.clone(), material);
+        }
+        Ok(table)
+    }
+
+    /// Applies the `[nodes.<name>]` tables to the scene graph under `root`.
+    /// `materials` holds the names a node's `material` may refer to.
+    pub fn apply_nodes(&self, root: &mut SceneNode, materials: &HashMap<&str, Material>) -> Result<(), String> {
+        for (name, settings) in &self.nodes {
+            let node = root
+                .find_mut(name)
+                .ok_or_else(|| format!("scene has no node named '{}'", name))?;
+
+            node.transform = settings.transform() * node.transform;
+            if let Some(visible) = settings.visible {
+                node.visible = visible;
+            }
+            if let Some(motion) = settings.motion {
+                node.motion = Some(motion.into());
+            }
+            if let Some(material) = &settings.material {
+                let material = materials
+                    .get(material.as_str())
+                    .ok_or_else(|| format!("node '{}': unknown material '{}'", name, material))?;
+                node.material = Some(material.clone());
+            }
+        }
+        Ok(())
+    }
+}
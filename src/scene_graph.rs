@@ -0,0 +1,206 @@
+use nalgebra_glm::{Mat4, Vec3, Vec4};
+use std::sync::Arc;
+
+use crate::cube::Cube;
+use crate::light::{Light, LightKind};
+use crate::material::Material;
+use crate::motion::{Motion, Moving};
+use crate::ray_intersect::RayIntersect;
+use crate::transform::{Instance, Transformed};
+
+/// Geometry held by a scene node, in the node's local space.
+pub enum Shape {
+    Block(Cube),
+    /// Geometry shared between placements; material overrides don't reach inside it.
+    Shared(Arc<dyn RayIntersect>),
+}
+
+/// A named node of the scene graph. The transform, visibility, motion and material
+/// override of a node apply to its own shapes and to everything below it.
+pub struct SceneNode {
+    pub name: String,
+    /// Placement relative to the parent node.
+    pub transform: Mat4,
+    pub visible: bool,
+    /// Replaces the material of every block in the subtree, unless a descendant overrides it again.
+    pub material: Option<Material>,
+    /// Moves the subtree in world space, turning or scaling it about this node's origin.
+    /// Motions don't stack: the nearest moving ancestor wins.
+    pub motion: Option<Motion>,
+    pub shapes: Vec<Shape>,
+    /// Lights placed in the node's space, e.g. imported with a model.
+    pub lights: Vec<Light>,
+    pub children: Vec<SceneNode>,
+}
+
+impl SceneNode {
+    pub fn new(name: &str) -> Self {
+        SceneNode {
+            name: name.to_string(),
+            transform: Mat4::identity(),
+            visible: true,
+            material: None,
+            motion: None,
+            shapes: Vec::new(),
+            lights: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_transform(mut self, transform: Mat4) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    pub fn add_block(&mut self, cube: Cube) {
+        self.shapes.push(Shape::Block(cube));
+    }
+
+    pub fn add_shared(&mut self, object: Arc<dyn RayIntersect>) {
+        self.shapes.push(Shape::Shared(object));
+    }
+
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn add_child(&mut self, child: SceneNode) {
+        self.children.push(child);
+    }
+
+    /// The first node called `name` in this subtree, depth first.
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut SceneNode> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|child| child.find_mut(name))
+    }
+
+    /// Every visible shape of the subtree placed in world space, ready to trace.
+    /// Blocks that are only moved or scaled stay plain axis-aligned cubes; anything
+    /// rotated is wrapped in a `Transformed`. Fails if a node's placement can't be
+    /// inverted, e.g. one scaled to zero.
+    pub fn flatten(&self) -> Result<Vec<Box<dyn RayIntersect>>, String> {
+        let mut objects = Vec::new();
+        self.flatten_into(&Mat4::identity(), None, None, &mut objects)?;
+        Ok(objects)
+    }
+
+    /// Every light of the visible nodes, placed in world space.
+    pub fn flatten_lights(&self) -> Vec<Light> {
+        let mut lights = Vec::new();
+        self.flatten_lights_into(&Mat4::identity(), None, &mut lights);
+        lights
+    }
+
+    /// The node's own motion in world space, turning and scaling about the node's origin.
+    fn world_motion(&self, world: &Mat4) -> Option<Motion> {
+        let origin = (world * Vec4::new(0.0, 0.0, 0.0, 1.0)).xyz();
+        self.motion.map(|motion| motion.about(origin))
+    }
+
+    fn flatten_lights_into(&self, parent: &Mat4, motion: Option<Motion>, lights: &mut Vec<Light>) {
+        if !self.visible {
+            return;
+        }
+
+        let world = parent * self.transform;
+        let motion = self.world_motion(&world).or(motion);
+        let direction = |d: Vec3| (world * Vec4::new(d.x, d.y, d.z, 0.0)).xyz().normalize();
+
+        for light in &self.lights {
+            let mut light = light.clone();
+            let p = light.position;
+            light.position = (world * Vec4::new(p.x, p.y, p.z, 1.0)).xyz();
+            match &mut light.kind {
+                LightKind::Directional { direction: d } | LightKind::Spot { direction: d, .. } => {
+                    *d = direction(*d);
+                }
+                LightKind::Cuboid { half_axes, .. } => {
+                    *half_axes = world.fixed_view::<3, 3>(0, 0) * *half_axes;
+                }
+                LightKind::Point { .. } | LightKind::Sphere { .. } => {}
+            }
+            if let Some(motion) = motion {
+                light = light.with_motion(motion);
+            }
+            lights.push(light);
+        }
+
+        for child in &self.children {
+            child.flatten_lights_into(&world, motion, lights);
+        }
+    }
+
+    fn flatten_into(
+        &self,
+        parent: &Mat4,
+        material: Option<&Material>,
+        motion: Option<Motion>,
+        objects: &mut Vec<Box<dyn RayIntersect>>,
+    ) -> Result<(), String> {
+        if !self.visible {
+            return Ok(());
+        }
+
+        let world = parent * self.transform;
+        let material = self.material.as_ref().or(material);
+        let motion = self.world_motion(&world).or(motion);
+
+        let placement_error = |e: String| format!("node '{}': {}", self.name, e);
+        for shape in &self.shapes {
+            let object: Box<dyn RayIntersect> = match shape {
+                Shape::Block(cube) => {
+                    let mut cube = cube.clone();
+                    if let Some(material) = material {
+                        cube.material = material.clone();
+                        cube.face_materials = None;
+                    }
+                    place_block(cube, &world, motion).map_err(placement_error)?
+                }
+                Shape::Shared(object) => {
+                    let instance = Instance::new(object.clone(), world).map_err(placement_error)?;
+                    match motion {
+                        Some(motion) => Box::new(Moving::new(instance, motion)),
+                        None => Box::new(instance),
+                    }
+                }
+            };
+            objects.push(object);
+        }
+
+        for child in &self.children {
+            child.flatten_into(&world, material, motion, objects)?;
+        }
+        Ok(())
+    }
+}
+
+fn place_block(mut cube: Cube, world: &Mat4, motion: Option<Motion>) -> Result<Box<dyn RayIntersect>, String> {
+    // Only a positive scale on each axis, so the transformed corners still bound the block.
+    let axis_aligned = (0..3).all(|row| {
+        (0..3).all(|col| {
+            if row == col {
+                world[(row, col)] > 0.0
+            } else {
+                world[(row, col)] == 0.0
+            }
+        })
+    });
+
+    if axis_aligned {
+        let point = |p: Vec3| (world * Vec4::new(p.x, p.y, p.z, 1.0)).xyz();
+        cube.min = point(cube.min);
+        cube.max = point(cube.max);
+        return Ok(match motion {
+            Some(motion) => Box::new(Moving::new(cube, motion)),
+            None => Box::new(cube),
+        });
+    }
+
+    let placed = Transformed::new(cube, *world)?;
+    Ok(match motion {
+        Some(motion) => Box::new(Moving::new(placed, motion)),
+        None => Box::new(placed),
+    })
+}
@@ -0,0 +1,347 @@
+use nalgebra_glm::Vec3;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+use crate::cube::Cube;
+use crate::material::Material;
+use crate::nbt::{self, Tag};
+use crate::structure::{self, Cell};
+
+/// Blocks that are left out rather than placed.
+const EMPTY_BLOCKS: [&str; 4] = ["air", "cave_air", "void_air", "structure_void"];
+
+/// Block names the built-in scene materials stand in for, before any `[blocks]` from the scene file.
+pub const DEFAULT_BLOCK_MATERIALS: [(&str, &str); 10] = [
+    ("snow_block", "snow"),
+    ("snow", "snow"),
+    ("powder_snow", "snow"),
+    ("stone", "stone"),
+    ("cobblestone", "stone"),
+    ("stone_bricks", "stone"),
+    ("ice", "ice"),
+    ("packed_ice", "packed_ice"),
+    ("blue_ice", "packed_ice"),
+    ("glowstone", "glowstone"),
+];
+
+/// A Minecraft build: every non-air block with its name, namespace and block
+/// state stripped for vanilla blocks (`minecraft:oak_stairs[facing=east]` is `oak_stairs`).
+#[derive(Debug, Clone)]
+pub struct Schematic {
+    pub size: [i32; 3],
+    pub blocks: Vec<(Cell, String)>,
+}
+
+impl Schematic {
+    /// Reads a Sponge `.schem` (versions 1 to 3), an MCEdit `.schematic` or a structure block `.nbt` file.
+    pub fn load(path: &str) -> Result<Schematic, String> {
+        let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let (_, root) = nbt::parse(&data).map_err(|e| format!("{}: {}", path, e))?;
+
+        // Sponge version 3 nests everything in a `Schematic` compound.
+        let schematic = root.get("Schematic").unwrap_or(&root);
+        let blocks = schematic.get("Blocks");
+        let parsed = if schematic.get("BlockData").is_some() || blocks.and_then(|b| b.get("Data")).is_some() {
+            Schematic::from_sponge(schematic)
+        } else if blocks.and_then(Tag::as_bytes).is_some() {
+            Schematic::from_mcedit(schematic)
+        } else if root.get("palette").is_some() || root.get("palettes").is_some() {
+            Schematic::from_structure(&root)
+        } else {
+            Err("not a schematic or structure file".to_string())
+        };
+        parsed.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn from_sponge(schematic: &Tag) -> Result<Schematic, String> {
+        let size = dimensions(schematic)?;
+        let blocks = schematic.get("Blocks");
+        let palette = blocks
+            .and_then(|blocks| blocks.get("Palette"))
+            .or_else(|| schematic.get("Palette"))
+            .and_then(Tag::as_compound)
+            .ok_or("missing block palette")?;
+        let data = blocks
+            .and_then(|blocks| blocks.get("Data"))
+            .or_else(|| schematic.get("BlockData"))
+            .and_then(Tag::as_bytes)
+            .ok_or("missing block data")?;
+
+        let mut names = HashMap::new();
+        for (name, index) in palette {
+            names.insert(index.as_int().ok_or("palette index is not a number")?, block_name(name));
+        }
+
+        // Palette indices are packed as unsigned LEB128 varints, ordered x, then z, then y.
+        let mut blocks = Vec::new();
+        let mut bytes = data.iter();
+        for index in 0..volume(size)? {
+            let mut value = 0i64;
+            let mut shift = 0;
+            loop {
+                let byte = *bytes.next().ok_or("block data is shorter than the schematic")?;
+                value |= ((byte & 0x7f) as i64) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+                if shift > 35 {
+                    return Err("malformed block data".to_string());
+                }
+            }
+            let name = names.get(&value).ok_or("block data refers to a missing palette entry")?;
+            blocks.push((cell_at(index, size), name.clone()));
+        }
+        Ok(Schematic::new(size, blocks))
+    }
+
+    /// The pre-1.13 format, with numeric block ids.
+    fn from_mcedit(schematic: &Tag) -> Result<Schematic, String> {
+        let size = dimensions(schematic)?;
+        let ids = schematic.get("Blocks").and_then(Tag::as_bytes).ok_or("missing block ids")?;
+        let volume = volume(size)?;
+        if ids.len() < volume {
+            return Err("block ids are shorter than the schematic".to_string());
+        }
+
+        let blocks = (0..volume)
+            .map(|index| (cell_at(index, size), legacy_block_name(ids[index])))
+            .collect();
+        Ok(Schematic::new(size, blocks))
+    }
+
+    fn from_structure(root: &Tag) -> Result<Schematic, String> {
+        let size = root.get("size").and_then(Tag::as_list).ok_or("missing structure size")?;
+        let size: Vec<i32> = size
+            .iter()
+            .filter_map(|value| value.as_int())
+            .map(|value| value as i32)
+            .collect();
+        let size: [i32; 3] = size.try_into().map_err(|_| "structure size needs three numbers")?;
+
+        // Structures with random variants keep several palettes; the first is used.
+        let palette = match root.get("palette") {
+            Some(palette) => palette.as_list(),
+            None => root
+                .get("palettes")
+                .and_then(Tag::as_list)
+                .and_then(|palettes| palettes.first())
+                .and_then(Tag::as_list),
+        }
+        .ok_or("missing structure palette")?;
+        let names: Vec<String> = palette
+            .iter()
+            .map(|state| state.get("Name").and_then(Tag::as_str).map(block_name))
+            .collect::<Option<_>>()
+            .ok_or("palette entry without a name")?;
+
+        let entries = root.get("blocks").and_then(Tag::as_list).ok_or("missing structure blocks")?;
+        let mut blocks = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let pos: Vec<i32> = entry
+                .get("pos")
+                .and_then(Tag::as_list)
+                .ok_or("block without a position")?
+                .iter()
+                .filter_map(|value| value.as_int())
+                .map(|value| value as i32)
+                .collect();
+            let cell: Cell = pos.try_into().map_err(|_| "block position needs three numbers")?;
+            let state = entry.get("state").and_then(Tag::as_int).ok_or("block without a state")?;
+            let name = names.get(state as usize).ok_or("block refers to a missing palette entry")?;
+            blocks.push((cell, name.clone()));
+        }
+        Ok(Schematic::new(size, blocks))
+    }
+
+    fn new(size: [i32; 3], mut blocks: Vec<(Cell, String)>) -> Schematic {
+        blocks.retain(|(_, name)| !EMPTY_BLOCKS.contains(&name.as_str()));
+        Schematic { size, blocks }
+    }
+
+    /// Blocks of `block_size` with the build's footprint centered over `origin`.
+    /// Block names are looked up in `materials`; any without an entry get
+    /// `placeholder` and are returned with how often they occur. Blocks boxed in by
+    /// opaque neighbours on all six sides are left out.
+    pub fn cubes(
+        &self,
+        origin: Vec3,
+        block_size: f32,
+        materials: &HashMap<String, Material>,
+        placeholder: &Material,
+    ) -> (Vec<Cube>, BTreeMap<String, usize>) {
+        let (half_x, half_z) = (self.size[0] / 2, self.size[2] / 2);
+        let cell = |[x, y, z]: Cell| -> Cell { [x - half_x, y, z - half_z] };
+
+        let opaque: HashSet<Cell> = self
+            .blocks
+            .iter()
+            .filter(|(_, name)| materials.get(name).is_some_and(is_opaque))
+            .map(|(position, _)| cell(*position))
+            .collect();
+
+        let mut cubes = Vec::new();
+        let mut unknown = BTreeMap::new();
+        for (position, name) in &self.blocks {
+            let material = materials.get(name).unwrap_or_else(|| {
+                *unknown.entry(name.clone()).or_insert(0) += 1;
+                placeholder
+            });
+
+            let cell = cell(*position);
+            if structure::is_enclosed(cell, &opaque) {
+                continue;
+            }
+            let (min, max) = structure::cell_bounds(cell, origin, block_size);
+            cubes.push(Cube::new(min, max, material.clone()));
+        }
+        (cubes, unknown)
+    }
+}
+
+fn is_opaque(material: &Material) -> bool {
+    material.albedo[3] <= 0.0 && material.alpha_cutoff.is_none()
+}
+
+fn dimensions(schematic: &Tag) -> Result<[i32; 3], String> {
+    let dimension = |key: &str| {
+        schematic
+            .get(key)
+            .and_then(Tag::as_int)
+            // Sizes are stored as signed shorts but mean unsigned ones.
+            .map(|value| value as u16 as i32)
+            .ok_or_else(|| format!("missing {}", key))
+    };
+    Ok([dimension("Width")?, dimension("Height")?, dimension("Length")?])
+}
+
+/// Number of blocks in a box of `size`, without overflowing for the largest sizes a file can claim.
+fn volume([width, height, length]: [i32; 3]) -> Result<usize, String> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|area| area.checked_mul(length as usize))
+        .ok_or_else(|| format!("a {}x{}x{} schematic is too large", width, height, length))
+}
+
+/// Cell of the block at `index` in a box of `size`, counting along x, then z, then y.
+fn cell_at(index: usize, [width, _, length]: [i32; 3]) -> Cell {
+    let (width, length) = (width as usize, length as usize);
+    [
+        (index % width) as i32,
+        (index / (width * length)) as i32,
+        ((index / width) % length) as i32,
+    ]
+}
+
+fn block_name(state: &str) -> String {
+    let name = state.split('[').next().unwrap_or(state);
+    name.strip_prefix("minecraft:").unwrap_or(name).to_string()
+}
+
+/// Modern names for the common pre-1.13 numeric ids; others become `legacy_<id>`.
+fn legacy_block_name(id: u8) -> String {
+    let name = match id {
+        0 => "air",
+        1 => "stone",
+        2 => "grass_block",
+        3 => "dirt",
+        4 => "cobblestone",
+        5 => "oak_planks",
+        7 => "bedrock",
+        8 | 9 => "water",
+        10 | 11 => "lava",
+        12 => "sand",
+        13 => "gravel",
+        17 => "oak_log",
+        18 => "oak_leaves",
+        20 => "glass",
+        24 => "sandstone",
+        35 => "white_wool",
+        45 => "bricks",
+        49 => "obsidian",
+        78 => "snow",
+        79 => "ice",
+        80 => "snow_block",
+        82 => "clay",
+        87 => "netherrack",
+        89 => "glowstone",
+        98 => "stone_bricks",
+        174 => "packed_ice",
+        _ => return format!("legacy_{}", id),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compound(fields: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(fields.into_iter().map(|(name, tag)| (name.to_string(), tag)).collect())
+    }
+
+    fn varint(mut value: u32, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    /// A version 2 Sponge schematic whose block at index `i` uses palette entry `indices[i]`.
+    fn sponge(size: [i16; 3], palette: &[(&str, i32)], indices: &[u32]) -> Tag {
+        let mut data = Vec::new();
+        indices.iter().for_each(|&index| varint(index, &mut data));
+        compound(vec![
+            ("Width", Tag::Short(size[0])),
+            ("Height", Tag::Short(size[1])),
+            ("Length", Tag::Short(size[2])),
+            (
+                "Palette",
+                compound(palette.iter().map(|&(name, index)| (name, Tag::Int(index))).collect()),
+            ),
+            ("BlockData", Tag::ByteArray(data)),
+        ])
+    }
+
+    #[test]
+    fn decodes_multi_byte_palette_indices() {
+        let palette = [("minecraft:stone", 0), ("minecraft:oak_stairs[facing=east]", 300)];
+        let schematic = Schematic::from_sponge(&sponge([2, 1, 1], &palette, &[300, 0])).unwrap();
+        assert_eq!(
+            schematic.blocks,
+            [([0, 0, 0], "oak_stairs".to_string()), ([1, 0, 0], "stone".to_string())]
+        );
+    }
+
+    #[test]
+    fn orders_blocks_along_x_then_z_then_y() {
+        let palette: Vec<(String, i32)> = (0..8).map(|index| (format!("block_{}", index), index)).collect();
+        let palette: Vec<(&str, i32)> = palette.iter().map(|(name, index)| (name.as_str(), *index)).collect();
+        let schematic = Schematic::from_sponge(&sponge([2, 2, 2], &palette, &[0, 1, 2, 3, 4, 5, 6, 7])).unwrap();
+
+        let cell_of = |name: &str| schematic.blocks.iter().find(|(_, block)| block == name).unwrap().0;
+        assert_eq!(cell_of("block_1"), [1, 0, 0]);
+        assert_eq!(cell_of("block_2"), [0, 0, 1]);
+        assert_eq!(cell_of("block_4"), [0, 1, 0]);
+        assert_eq!(cell_of("block_7"), [1, 1, 1]);
+    }
+
+    #[test]
+    fn leaves_out_air_and_rejects_bad_block_data() {
+        let palette = [("minecraft:air", 0), ("minecraft:snow_block", 1)];
+        let schematic = Schematic::from_sponge(&sponge([3, 1, 1], &palette, &[0, 1, 0])).unwrap();
+        assert_eq!(schematic.blocks, [([1, 0, 0], "snow_block".to_string())]);
+
+        assert!(Schematic::from_sponge(&sponge([3, 1, 1], &palette, &[0, 1])).is_err());
+        assert!(Schematic::from_sponge(&sponge([1, 1, 1], &palette, &[2])).is_err());
+
+        // Stored as signed shorts, -1 is the largest size a file can claim.
+        let error = Schematic::from_sponge(&sponge([-1, -1, -1], &palette, &[1])).unwrap_err();
+        assert!(error.contains("shorter than the schematic"), "{}", error);
+    }
+}
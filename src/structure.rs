@@ -0,0 +1,253 @@
+use nalgebra_glm::Vec3;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::cube::Cube;
+use crate::material::Material;
+
+/// A block position in grid cells: x and z across the ground, y up from the base.
+pub type Cell = [i32; 3];
+
+/// The direction a doorway opens towards. North is -Z.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Facing {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Facing {
+    /// One cell outwards, as (x, z).
+    fn step(self) -> (i32, i32) {
+        match self {
+            Facing::North => (0, -1),
+            Facing::South => (0, 1),
+            Facing::East => (1, 0),
+            Facing::West => (-1, 0),
+        }
+    }
+
+    /// Whether `(x, z)`, relative to the center, lies in a one block wide, two high
+    /// doorway cut through the wall on this side.
+    fn is_doorway(self, x: i32, y: i32, z: i32) -> bool {
+        let (dx, dz) = self.step();
+        let outwards = x * dx + z * dz;
+        let across = x * dz - z * dx;
+        outwards > 0 && across == 0 && y < 2
+    }
+}
+
+/// Half-ellipsoid shell of blocks; an igloo when it has a door.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dome {
+    pub center: [i32; 2],
+    /// Horizontal radius in blocks.
+    pub radius: f32,
+    pub height: f32,
+    pub wall_thickness: f32,
+    pub door: Option<Facing>,
+}
+
+impl Default for Dome {
+    fn default() -> Self {
+        Dome {
+            center: [0, 0],
+            radius: 3.5,
+            height: 4.0,
+            wall_thickness: 1.0,
+            door: Some(Facing::South),
+        }
+    }
+}
+
+impl Dome {
+    pub fn cells(&self) -> Vec<Cell> {
+        let inside = |x: i32, y: i32, z: i32, radius: f32, height: f32| {
+            if radius <= 0.0 || height <= 0.0 {
+                return false;
+            }
+            // Measured at the middle of the block's height so the bottom row isn't cut short.
+            let (x, y, z) = (x as f32, y as f32 + 0.5, z as f32);
+            (x * x + z * z) / (radius * radius) + y * y / (height * height) <= 1.0
+        };
+
+        let reach = self.radius.ceil() as i32;
+        let inner_radius = self.radius - self.wall_thickness;
+        let inner_height = self.height - self.wall_thickness;
+        let mut cells = Vec::new();
+        for y in 0..self.height.ceil() as i32 {
+            for z in -reach..=reach {
+                for x in -reach..=reach {
+                    let in_wall = inside(x, y, z, self.radius, self.height)
+                        && !inside(x, y, z, inner_radius, inner_height);
+                    let in_door = self.door.is_some_and(|door| door.is_doorway(x, y, z));
+                    if in_wall && !in_door {
+                        cells.push([self.center[0] + x, y, self.center[1] + z]);
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// Straight wall between two ground cells, optionally with alternating merlons on top.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Wall {
+    pub start: [i32; 2],
+    pub end: [i32; 2],
+    pub height: i32,
+    pub thickness: i32,
+    pub crenellated: bool,
+}
+
+impl Default for Wall {
+    fn default() -> Self {
+        Wall {
+            start: [0, 0],
+            end: [4, 0],
+            height: 2,
+            thickness: 1,
+            crenellated: false,
+        }
+    }
+}
+
+impl Wall {
+    pub fn cells(&self) -> Vec<Cell> {
+        let (dx, dz) = (self.end[0] - self.start[0], self.end[1] - self.start[1]);
+        let steps = dx.abs().max(dz.abs());
+        // Thickness grows across the wall's main direction, centered on the line.
+        let (across_x, across_z) = if dx.abs() >= dz.abs() { (0, 1) } else { (1, 0) };
+        let first = -(self.thickness - 1) / 2;
+
+        let mut cells = Vec::new();
+        for i in 0..=steps {
+            let t = if steps > 0 { i as f32 / steps as f32 } else { 0.0 };
+            let x = self.start[0] + (dx as f32 * t).round() as i32;
+            let z = self.start[1] + (dz as f32 * t).round() as i32;
+            let top = if self.crenellated && i % 2 == 0 {
+                self.height + 1
+            } else {
+                self.height
+            };
+            for offset in first..first + self.thickness {
+                for y in 0..top {
+                    cells.push([x + across_x * offset, y, z + across_z * offset]);
+                }
+            }
+        }
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    }
+}
+
+/// Round hollow tower with optional battlements and door.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tower {
+    pub center: [i32; 2],
+    pub radius: f32,
+    pub height: i32,
+    pub battlements: bool,
+    pub door: Option<Facing>,
+}
+
+impl Default for Tower {
+    fn default() -> Self {
+        Tower {
+            center: [0, 0],
+            radius: 2.0,
+            height: 6,
+            battlements: true,
+            door: None,
+        }
+    }
+}
+
+impl Tower {
+    pub fn cells(&self) -> Vec<Cell> {
+        let reach = self.radius.ceil() as i32;
+        let inner = (self.radius - 1.0).max(0.0);
+        let mut cells = Vec::new();
+        for z in -reach..=reach {
+            for x in -reach..=reach {
+                let distance_squared = (x * x + z * z) as f32;
+                if distance_squared > self.radius * self.radius || distance_squared < inner * inner {
+                    continue;
+                }
+                let top = if self.battlements && (x + z) % 2 == 0 {
+                    self.height + 1
+                } else {
+                    self.height
+                };
+                for y in 0..top {
+                    if !self.door.is_some_and(|door| door.is_doorway(x, y, z)) {
+                        cells.push([self.center[0] + x, y, self.center[1] + z]);
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// Any generated structure, tagged by `type` in scene files.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Structure {
+    Dome(Dome),
+    Wall(Wall),
+    Tower(Tower),
+}
+
+impl Structure {
+    pub fn cells(&self) -> Vec<Cell> {
+        match self {
+            Structure::Dome(dome) => dome.cells(),
+            Structure::Wall(wall) => wall.cells(),
+            Structure::Tower(tower) => tower.cells(),
+        }
+    }
+}
+
+/// Corners of the block of `block_size` at `cell`, with cell `[0, 0, 0]` standing centered on `origin`.
+pub fn cell_bounds(cell: Cell, origin: Vec3, block_size: f32) -> (Vec3, Vec3) {
+    let [x, y, z] = cell;
+    let min = origin + Vec3::new(x as f32 - 0.5, y as f32, z as f32 - 0.5) * block_size;
+    (min, min + Vec3::new(block_size, block_size, block_size))
+}
+
+/// Whether all six neighbours of `cell` are in `opaque`, hiding it from every side.
+pub fn is_enclosed(cell: Cell, opaque: &HashSet<Cell>) -> bool {
+    let [x, y, z] = cell;
+    [
+        [x + 1, y, z],
+        [x - 1, y, z],
+        [x, y + 1, z],
+        [x, y - 1, z],
+        [x, y, z + 1],
+        [x, y, z - 1],
+    ]
+    .iter()
+    .all(|neighbour| opaque.contains(neighbour))
+}
+
+/// Blocks of `block_size` for `cells`, with cell `[0, 0, 0]` centered over `origin`.
+/// Materials alternate in a checkerboard across the ground, `even` where `x + z` is
+/// even, so stacked rows line up like the hand-built igloo's ice and packed ice.
+pub fn blocks(cells: &[Cell], origin: Vec3, block_size: f32, even: &Material, odd: &Material) -> Vec<Cube> {
+    cells
+        .iter()
+        .map(|&[x, y, z]| {
+            let (min, max) = cell_bounds([x, y, z], origin, block_size);
+            let material = if (x + z).rem_euclid(2) == 0 { even } else { odd };
+            Cube::new(min, max, material.clone())
+        })
+        .collect()
+}
@@ -0,0 +1,101 @@
+use nalgebra_glm::Vec3;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::procedural::Perlin;
+use crate::structure::Cell;
+
+/// What a ground block is made of; the caller picks the materials.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ground {
+    Stone,
+    Snow,
+    /// Stone with a snow-covered top, for hillsides whose sides show.
+    SnowyStone,
+    Glowstone,
+}
+
+/// The ground the structures stand on, in blocks below and around cell `[0, 0, 0]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Terrain {
+    /// A one block thick square slab `size` blocks across.
+    Flat { size: i32 },
+    /// Rolling snow hills from fractal noise, up to `height` blocks above the slab.
+    /// Hills flatten out within `clearing` blocks of the center so structures stand on level ground.
+    Noise {
+        size: i32,
+        height: f32,
+        /// Noise features per block; smaller values give broader hills.
+        scale: f32,
+        octaves: u32,
+        clearing: f32,
+    },
+}
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Terrain::Flat { size: 9 }
+    }
+}
+
+impl Terrain {
+    /// Ground blocks, with a few glowstones scattered over the surface. All
+    /// randomness comes from `rng`, so the same seed gives the same ground.
+    pub fn cells(&self, rng: &mut StdRng) -> Vec<(Cell, Ground)> {
+        let mut cells = Vec::new();
+        match *self {
+            Terrain::Flat { size } => {
+                for z in columns(size) {
+                    for x in columns(size) {
+                        let ground = if rng.gen::<f32>() < 0.05 {
+                            Ground::Glowstone
+                        } else if rng.gen::<bool>() {
+                            Ground::Snow
+                        } else {
+                            Ground::Stone
+                        };
+                        cells.push(([x, -1, z], ground));
+                    }
+                }
+            }
+            Terrain::Noise {
+                size,
+                height,
+                scale,
+                octaves,
+                clearing,
+            } => {
+                let perlin = Perlin::new(rng.gen());
+                for z in columns(size) {
+                    for x in columns(size) {
+                        let noise = perlin.fbm(&(Vec3::new(x as f32, 0.5, z as f32) * scale), octaves, 2.0, 0.5);
+                        let distance = ((x * x + z * z) as f32).sqrt();
+                        let rise = ((distance - clearing) / 2.0).clamp(0.0, 1.0);
+                        // fbm strays a little outside [-1, 1]; clamped so columns never dip below the slab.
+                        let level = (noise * 0.5 + 0.5).clamp(0.0, 1.0);
+                        let top = -1 + (level * height.max(0.0) * rise).round() as i32;
+
+                        for y in -1..top {
+                            cells.push(([x, y, z], Ground::Stone));
+                        }
+                        let surface = if rng.gen::<f32>() < 0.05 {
+                            Ground::Glowstone
+                        } else {
+                            Ground::SnowyStone
+                        };
+                        cells.push(([x, top, z], surface));
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// Cell coordinates of `size` columns centered on 0; even sizes reach one further on the negative side.
+fn columns(size: i32) -> std::ops::Range<i32> {
+    let first = -size / 2;
+    first..first + size
+}
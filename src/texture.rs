@@ -1,64 +1,510 @@
 extern crate image;
 use image::{ImageReader, Pixel, DynamicImage, GenericImageView};
+use nalgebra_glm::Vec3;
+use serde::Deserialize;
 use std::fmt;
+use std::sync::Arc;
+use crate::atlas::AtlasTile;
 use crate::color::Color;
+use crate::procedural::{ProceduralTexture, TextureSpace};
+use crate::ray_intersect::Intersect;
+
+/// How texels are looked up and blended when a texture is sampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    /// Closest texel, keeps the blocky pixel-art look.
+    Nearest,
+    /// Blend of the four closest texels on the full-resolution image.
+    Bilinear,
+    /// Bilinear lookups on the two closest mip levels, blended by level of detail.
+    Trilinear,
+    /// Several trilinear lookups along the long axis of the pixel footprint,
+    /// which keeps surfaces seen at grazing angles sharp.
+    Anisotropic { max_samples: u32 },
+}
+
+/// What happens to texture coordinates outside `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+    Border(Color),
+}
+
+impl WrapMode {
+    /// Maps a texel index onto `0..size`, or `None` when it falls on the border.
+    fn wrap(&self, i: i64, size: usize) -> Option<usize> {
+        let size = size as i64;
+        match self {
+            WrapMode::Repeat => Some(i.rem_euclid(size) as usize),
+            WrapMode::Clamp => Some(i.clamp(0, size - 1) as usize),
+            WrapMode::Mirror => {
+                let period = i.rem_euclid(2 * size);
+                let i = if period < size { period } else { 2 * size - 1 - period };
+                Some(i as usize)
+            }
+            WrapMode::Border(_) => {
+                if (0..size).contains(&i) {
+                    Some(i as usize)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn border_color(&self) -> Color {
+        match self {
+            WrapMode::Border(color) => *color,
+            _ => Color::black(),
+        }
+    }
+}
+
+/// Screen-space derivatives of the texture coordinates: how far `(u, v)` moves
+/// across one pixel along each of the two footprint axes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UvDerivatives {
+    pub dudx: f32,
+    pub dvdx: f32,
+    pub dudy: f32,
+    pub dvdy: f32,
+}
+
+#[derive(Clone)]
+struct MipLevel {
+    width: usize,
+    height: usize,
+    color_array: Vec<Color>,
+    alpha_array: Vec<u8>,
+}
+
+impl MipLevel {
+    /// `wrap` holds the modes along u and along v.
+    fn texel(&self, x: i64, y: i64, wrap: (WrapMode, WrapMode)) -> Color {
+        match (wrap.0.wrap(x, self.width), wrap.1.wrap(y, self.height)) {
+            (Some(x), Some(y)) => self.color_array[y * self.width + x],
+            (None, _) => wrap.0.border_color(),
+            (_, None) => wrap.1.border_color(),
+        }
+    }
+
+    fn texel_alpha(&self, x: i64, y: i64, wrap: (WrapMode, WrapMode)) -> f32 {
+        match (wrap.0.wrap(x, self.width), wrap.1.wrap(y, self.height)) {
+            (Some(x), Some(y)) => self.alpha_array[y * self.width + x] as f32 / 255.0,
+            _ => 1.0,
+        }
+    }
+
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut color_array = Vec::with_capacity(width * height);
+        let mut alpha_array = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(self.width - 1);
+                    let sy = (y * 2 + dy).min(self.height - 1);
+                    let c = self.color_array[sy * self.width + sx];
+                    sum[0] += c.r as u32;
+                    sum[1] += c.g as u32;
+                    sum[2] += c.b as u32;
+                    sum[3] += self.alpha_array[sy * self.width + sx] as u32;
+                }
+                color_array.push(Color::new(
+                    (sum[0] / 4) as u8,
+                    (sum[1] / 4) as u8,
+                    (sum[2] / 4) as u8,
+                ));
+                alpha_array.push((sum[3] / 4) as u8);
+            }
+        }
+
+        MipLevel {
+            width,
+            height,
+            color_array,
+            alpha_array,
+        }
+    }
+
+    fn nearest(&self, u: f32, v: f32, wrap: (WrapMode, WrapMode)) -> Color {
+        let x = (u * self.width as f32).floor() as i64;
+        let y = ((1.0 - v) * self.height as f32).floor() as i64;
+        self.texel(x, y, wrap)
+    }
+
+    fn bilinear(&self, u: f32, v: f32, wrap: (WrapMode, WrapMode)) -> Color {
+        let fx = u * self.width as f32 - 0.5;
+        let fy = (1.0 - v) * self.height as f32 - 0.5;
+        let x0 = fx.floor();
+        let y0 = fy.floor();
+        let tx = fx - x0;
+        let ty = fy - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = lerp_color(self.texel(x0, y0, wrap), self.texel(x0 + 1, y0, wrap), tx);
+        let bottom = lerp_color(
+            self.texel(x0, y0 + 1, wrap),
+            self.texel(x0 + 1, y0 + 1, wrap),
+            tx,
+        );
+        lerp_color(top, bottom, ty)
+    }
+
+    fn nearest_alpha(&self, u: f32, v: f32, wrap: (WrapMode, WrapMode)) -> f32 {
+        let x = (u * self.width as f32).floor() as i64;
+        let y = ((1.0 - v) * self.height as f32).floor() as i64;
+        self.texel_alpha(x, y, wrap)
+    }
+
+    fn bilinear_alpha(&self, u: f32, v: f32, wrap: (WrapMode, WrapMode)) -> f32 {
+        let fx = u * self.width as f32 - 0.5;
+        let fy = (1.0 - v) * self.height as f32 - 0.5;
+        let x0 = fx.floor();
+        let y0 = fy.floor();
+        let tx = fx - x0;
+        let ty = fy - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let top = lerp(self.texel_alpha(x0, y0, wrap), self.texel_alpha(x0 + 1, y0, wrap), tx);
+        let bottom = lerp(
+            self.texel_alpha(x0, y0 + 1, wrap),
+            self.texel_alpha(x0 + 1, y0 + 1, wrap),
+            tx,
+        );
+        lerp(top, bottom, ty)
+    }
+}
+
+fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round().clamp(0.0, 255.0) as u8;
+    Color::new(mix(a.r, b.r), mix(a.g, b.g), mix(a.b, b.b))
+}
+
+/// Frame timing for a texture stored as a vertical strip of square frames,
+/// the way Minecraft animates glowstone, lava and water.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextureAnimation {
+    /// Seconds each frame stays on screen.
+    pub frame_time: f32,
+    /// Playback order as frame indices; empty plays every frame top to bottom.
+    #[serde(default)]
+    pub frames: Vec<usize>,
+    /// Cross-fade between consecutive frames instead of switching abruptly.
+    #[serde(default)]
+    pub interpolate: bool,
+}
+
+impl TextureAnimation {
+    pub fn new(frame_time: f32) -> Self {
+        TextureAnimation {
+            frame_time,
+            frames: Vec::new(),
+            interpolate: false,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Texture {
     image: DynamicImage,
     pub width: usize,
     pub height: usize,
-    color_array: Vec<Color>,
+    pub filter: FilterMode,
+    pub wrap: WrapMode,
+    /// Wrap mode along v when it differs from `wrap`, as glTF samplers allow.
+    pub wrap_v: Option<WrapMode>,
+    pub animation: Option<TextureAnimation>,
+    /// The image file the texture was loaded from; `None` for images decoded from elsewhere.
+    pub path: Option<String>,
+    /// One mip chain per animation frame; still images have a single frame.
+    frames: Vec<Vec<MipLevel>>,
 }
 
 impl Texture {
+    /// Panics if the image can't be read; for files named by the user, use `load`.
     pub fn new(file_path: &str) -> Texture {
-        let img = ImageReader::open(file_path).unwrap().decode().unwrap();
+        Texture::load(file_path).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn load(file_path: &str) -> Result<Texture, String> {
+        let img = ImageReader::open(file_path)
+            .map_err(|e| format!("could not open {}: {}", file_path, e))?
+            .decode()
+            .map_err(|e| format!("could not decode {}: {}", file_path, e))?;
+        let mut texture = Texture::from_image(img);
+        texture.path = Some(file_path.to_string());
+        Ok(texture)
+    }
+
+    /// A texture from an already decoded image, e.g. one embedded in a model file.
+    pub fn from_image(img: DynamicImage) -> Texture {
         let width = img.width() as usize;
         let height = img.height() as usize;
         let mut texture = Texture {
             image: img,
             width,
             height,
-            color_array: vec![Color::black(); width * height],
+            filter: FilterMode::Nearest,
+            wrap: WrapMode::Repeat,
+            wrap_v: None,
+            animation: None,
+            path: None,
+            frames: Vec::new(),
         };
         texture.load_color_array();
+        texture.build_mip_levels();
         texture
     }
 
+    /// The full source image, e.g. to write out an embedded texture.
+    pub fn image(&self) -> &DynamicImage {
+        &self.image
+    }
+
+    pub fn with_filter(mut self, filter: FilterMode) -> Texture {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_wrap(mut self, wrap: WrapMode) -> Texture {
+        self.wrap = wrap;
+        self.wrap_v = None;
+        self
+    }
+
+    /// Separate wrap modes along u and v.
+    pub fn with_wrap_uv(mut self, wrap_u: WrapMode, wrap_v: WrapMode) -> Texture {
+        self.wrap = wrap_u;
+        self.wrap_v = Some(wrap_v);
+        self
+    }
+
+    fn wraps(&self) -> (WrapMode, WrapMode) {
+        (self.wrap, self.wrap_v.unwrap_or(self.wrap))
+    }
+
+    /// Splits the image into square frames stacked top to bottom and plays them back over time.
+    pub fn with_animation(mut self, animation: TextureAnimation) -> Texture {
+        let frame_size = self.image.width() as usize;
+        self.width = frame_size;
+        self.height = frame_size.min(self.image.height() as usize);
+        self.animation = Some(animation);
+        self.load_color_array();
+        self.build_mip_levels();
+        self
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
     fn load_color_array(&mut self) {
-        for x in 0..self.width {
-            for y in 0..self.height {
-                let pixel = self.image.get_pixel(x as u32, y as u32).to_rgb();
-                let color = ((pixel[0] as u32) << 16) | ((pixel[1] as u32) << 8) | (pixel[2] as u32);
-                self.color_array[y * self.width + x] = Color::from_hex(color);
+        let frame_count = (self.image.height() as usize / self.height).max(1);
+        self.frames = (0..frame_count)
+            .map(|frame| {
+                let mut color_array = vec![Color::black(); self.width * self.height];
+                let mut alpha_array = vec![255; self.width * self.height];
+                for x in 0..self.width {
+                    for y in 0..self.height {
+                        let image_y = (frame * self.height + y) as u32;
+                        let pixel = self.image.get_pixel(x as u32, image_y).to_rgba();
+                        let color = ((pixel[0] as u32) << 16) | ((pixel[1] as u32) << 8) | (pixel[2] as u32);
+                        color_array[y * self.width + x] = Color::from_hex(color);
+                        alpha_array[y * self.width + x] = pixel[3];
+                    }
+                }
+                vec![MipLevel {
+                    width: self.width,
+                    height: self.height,
+                    color_array,
+                    alpha_array,
+                }]
+            })
+            .collect();
+    }
+
+    fn build_mip_levels(&mut self) {
+        for mip_levels in &mut self.frames {
+            while let Some(last) = mip_levels.last() {
+                if last.width == 1 && last.height == 1 {
+                    break;
+                }
+                let next = last.downsample();
+                mip_levels.push(next);
             }
         }
     }
 
+    /// Frames showing at `time` and how far to blend from the first to the second.
+    fn frames_at(&self, time: f32) -> (usize, usize, f32) {
+        let animation = match &self.animation {
+            Some(animation) if self.frames.len() > 1 && animation.frame_time > 0.0 => animation,
+            _ => return (0, 0, 0.0),
+        };
+
+        let order: Vec<usize> = if animation.frames.is_empty() {
+            (0..self.frames.len()).collect()
+        } else {
+            animation.frames.iter().map(|&frame| frame.min(self.frames.len() - 1)).collect()
+        };
+
+        let position = (time / animation.frame_time).max(0.0);
+        let step = position.floor() as usize;
+        let current = order[step % order.len()];
+        let next = order[(step + 1) % order.len()];
+        let blend = if animation.interpolate { position.fract() } else { 0.0 };
+        (current, next, blend)
+    }
+
+    /// Unfiltered lookup on the first frame at full resolution (bilinear if the texture asks for any blending).
     pub fn get_color(&self, u: f32, v: f32) -> Color {
-        let width = self.width as f32;
-        let height = self.height as f32;
+        match self.filter {
+            FilterMode::Nearest => self.frames[0][0].nearest(u, v, self.wraps()),
+            _ => self.frames[0][0].bilinear(u, v, self.wraps()),
+        }
+    }
+
+    /// Opacity in `[0, 1]` on the full-resolution frame shown at `time`; images without alpha are opaque.
+    pub fn get_alpha(&self, u: f32, v: f32, time: f32) -> f32 {
+        self.get_alpha_in(u, v, time, None)
+    }
 
-        
-        let u = u.fract();
-        let v = v.fract();
+    /// `get_alpha` for a lookup that must stay inside an atlas tile.
+    pub fn get_alpha_in_tile(&self, u: f32, v: f32, time: f32, tile: &AtlasTile) -> f32 {
+        self.get_alpha_in(u, v, time, Some(tile))
+    }
 
-        
-        let u = if u < 0.0 { u + 1.0 } else { u };
-        let v = if v < 0.0 { v + 1.0 } else { v };
+    fn get_alpha_in(&self, u: f32, v: f32, time: f32, tile: Option<&AtlasTile>) -> f32 {
+        let (frame, _, _) = self.frames_at(time);
+        let (u, v) = in_tile(tile, &self.frames[frame][0], u, v);
+        match self.filter {
+            FilterMode::Nearest => self.frames[frame][0].nearest_alpha(u, v, self.wraps()),
+            _ => self.frames[frame][0].bilinear_alpha(u, v, self.wraps()),
+        }
+    }
 
-        
-        let x = (u * width) as usize % self.width;
-        let y = ((1.0 - v) * height) as usize % self.height; 
+    /// Filtered lookup of the frame shown at `time`, using the pixel footprint to pick the mip level.
+    pub fn sample(&self, u: f32, v: f32, derivatives: &UvDerivatives, time: f32) -> Color {
+        self.sample_in(u, v, derivatives, time, None)
+    }
 
-        let index = y * self.width + x;
+    /// `sample` for a lookup that must stay inside an atlas tile, on every mip level it reads.
+    pub fn sample_in_tile(
+        &self,
+        u: f32,
+        v: f32,
+        derivatives: &UvDerivatives,
+        time: f32,
+        tile: &AtlasTile,
+    ) -> Color {
+        self.sample_in(u, v, derivatives, time, Some(tile))
+    }
 
-        if x >= self.width || y >= self.height {
-            Color::from_hex(0xFF00FF)
+    fn sample_in(
+        &self,
+        u: f32,
+        v: f32,
+        derivatives: &UvDerivatives,
+        time: f32,
+        tile: Option<&AtlasTile>,
+    ) -> Color {
+        let (current, next, blend) = self.frames_at(time);
+        let color = self.sample_frame(&self.frames[current], u, v, derivatives, tile);
+        if blend > 0.0 {
+            let next_color = self.sample_frame(&self.frames[next], u, v, derivatives, tile);
+            lerp_color(color, next_color, blend)
         } else {
-            self.color_array[index]
+            color
+        }
+    }
+
+    fn sample_frame(
+        &self,
+        mip_levels: &[MipLevel],
+        u: f32,
+        v: f32,
+        derivatives: &UvDerivatives,
+        tile: Option<&AtlasTile>,
+    ) -> Color {
+        // Footprint axes measured in texels of the full-resolution image.
+        let axis_x = (derivatives.dudx * self.width as f32, derivatives.dvdx * self.height as f32);
+        let axis_y = (derivatives.dudy * self.width as f32, derivatives.dvdy * self.height as f32);
+        let len_x = (axis_x.0 * axis_x.0 + axis_x.1 * axis_x.1).sqrt();
+        let len_y = (axis_y.0 * axis_y.0 + axis_y.1 * axis_y.1).sqrt();
+
+        match self.filter {
+            FilterMode::Nearest => {
+                let (u, v) = in_tile(tile, &mip_levels[0], u, v);
+                mip_levels[0].nearest(u, v, self.wraps())
+            }
+            FilterMode::Bilinear => {
+                let (u, v) = in_tile(tile, &mip_levels[0], u, v);
+                mip_levels[0].bilinear(u, v, self.wraps())
+            }
+            FilterMode::Trilinear => self.trilinear(mip_levels, u, v, len_x.max(len_y), tile),
+            FilterMode::Anisotropic { max_samples } => {
+                let (major, minor, du, dv) = if len_x >= len_y {
+                    (len_x, len_y, derivatives.dudx, derivatives.dvdx)
+                } else {
+                    (len_y, len_x, derivatives.dudy, derivatives.dvdy)
+                };
+
+                let ratio = if minor > 0.0 { major / minor } else { 1.0 };
+                let samples = ratio.ceil().clamp(1.0, max_samples.max(1) as f32) as u32;
+                let footprint = major / samples as f32;
+
+                let mut sum = [0.0f32; 3];
+                for i in 0..samples {
+                    let offset = (i as f32 + 0.5) / samples as f32 - 0.5;
+                    let c = self.trilinear(mip_levels, u + du * offset, v + dv * offset, footprint, tile);
+                    sum[0] += c.r as f32;
+                    sum[1] += c.g as f32;
+                    sum[2] += c.b as f32;
+                }
+                let n = samples as f32;
+                Color::new((sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8)
+            }
+        }
+    }
+
+    fn trilinear(
+        &self,
+        mip_levels: &[MipLevel],
+        u: f32,
+        v: f32,
+        footprint: f32,
+        tile: Option<&AtlasTile>,
+    ) -> Color {
+        let max_level = (mip_levels.len() - 1).min(tile.map_or(usize::MAX, |tile| tile.max_level(self.width, self.height)));
+        let lod = footprint.max(1e-6).log2().clamp(0.0, max_level as f32);
+        let lower = lod.floor() as usize;
+        let upper = (lower + 1).min(max_level);
+
+        let level = |index: usize| {
+            let (u, v) = in_tile(tile, &mip_levels[index], u, v);
+            mip_levels[index].bilinear(u, v, self.wraps())
+        };
+        let a = level(lower);
+        if upper == lower {
+            return a;
         }
+        lerp_color(a, level(upper), lod - lower as f32)
+    }
+}
+
+/// `(u, v)` kept inside `tile` on `level`, or unchanged without a tile.
+fn in_tile(tile: Option<&AtlasTile>, level: &MipLevel, u: f32, v: f32) -> (f32, f32) {
+    match tile {
+        Some(tile) => tile.clamp(u, v, level.width, level.height),
+        None => (u, v),
     }
 }
 
@@ -67,6 +513,80 @@ impl fmt::Debug for Texture {
         f.debug_struct("Texture")
             .field("width", &self.width)
             .field("height", &self.height)
+            .field("filter", &self.filter)
+            .field("wrap", &self.wraps())
+            .field("frames", &self.frames.len())
             .finish()
     }
 }
+
+/// Anything a material can read colors from: an image or a procedural pattern.
+#[derive(Debug, Clone)]
+pub enum TextureSource {
+    Image(Arc<Texture>),
+    Procedural(Arc<ProceduralTexture>),
+}
+
+impl TextureSource {
+    pub fn sample(&self, intersect: &Intersect) -> Color {
+        match self {
+            TextureSource::Image(texture) => texture.sample(
+                intersect.u,
+                intersect.v,
+                &intersect.uv_derivatives,
+                intersect.time,
+            ),
+            TextureSource::Procedural(procedural) => {
+                procedural.color(&intersect.texture_point(procedural.space))
+            }
+        }
+    }
+
+    /// Brightness in `[0, 1]` at the intersection's uv shifted by `(du, dv)`, for height maps.
+    /// With an atlas `tile` the shifted uv repeats inside the tile, as the diffuse texture does.
+    pub fn height_at(&self, intersect: &Intersect, du: f32, dv: f32, tile: Option<&AtlasTile>) -> f32 {
+        let (u, v) = (intersect.u + du, intersect.v + dv);
+        let color = match (self, tile) {
+            (TextureSource::Image(texture), Some(tile)) => {
+                let (u, v, derivatives) = tile.map(u, v, &intersect.uv_derivatives);
+                texture.sample_in_tile(u, v, &derivatives, intersect.time, tile)
+            }
+            (TextureSource::Image(texture), None) => {
+                texture.sample(u, v, &intersect.uv_derivatives, intersect.time)
+            }
+            (TextureSource::Procedural(procedural), _) => {
+                let point = match procedural.space {
+                    TextureSpace::Uv => Vec3::new(u, v, 0.0),
+                    space => intersect.texture_point(space) + intersect.dpdu * du + intersect.dpdv * dv,
+                };
+                return procedural.value(&point);
+            }
+        };
+        color.luminance()
+    }
+
+    /// Step in uv matching one texel, for finite differences; one texel of the
+    /// tile when lookups go through an atlas `tile`.
+    pub fn texel_size(&self, tile: Option<&AtlasTile>) -> (f32, f32) {
+        match (self, tile) {
+            (TextureSource::Image(texture), Some(tile)) => (
+                1.0 / ((tile.u_max - tile.u_min) * texture.width as f32).max(1.0),
+                1.0 / ((tile.v_max - tile.v_min) * texture.height as f32).max(1.0),
+            ),
+            (TextureSource::Image(texture), None) => (1.0 / texture.width as f32, 1.0 / texture.height as f32),
+            (TextureSource::Procedural(_), _) => (1e-3, 1e-3),
+        }
+    }
+}
+
+impl From<Arc<Texture>> for TextureSource {
+    fn from(texture: Arc<Texture>) -> Self {
+        TextureSource::Image(texture)
+    }
+}
+
+impl From<Arc<ProceduralTexture>> for TextureSource {
+    fn from(procedural: Arc<ProceduralTexture>) -> Self {
+        TextureSource::Procedural(procedural)
+    }
+}
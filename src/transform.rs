@@ -0,0 +1,136 @@
+use nalgebra_glm::{Mat3, Mat4, Vec3, Vec4};
+use std::sync::Arc;
+
+use crate::ray_intersect::{Intersect, RayIntersect};
+
+/// An object placed in the world by a 4x4 matrix, e.g. a rotated or scaled block.
+/// Rays are moved into object space with the inverse, and hits are moved back out,
+/// with normals transformed by the inverse transpose so they stay perpendicular.
+pub struct Transformed<T: RayIntersect> {
+    pub object: T,
+    matrix: Mat4,
+    inverse: Mat4,
+    normal_matrix: Mat3,
+}
+
+/// Shares one object between many placements without copying its geometry or materials.
+pub type Instance = Transformed<Arc<dyn RayIntersect>>;
+
+impl<T: RayIntersect> Transformed<T> {
+    /// Fails if `matrix` can't be inverted, e.g. a zero scale.
+    pub fn new(object: T, matrix: Mat4) -> Result<Self, String> {
+        let inverse = matrix
+            .try_inverse()
+            .ok_or("transform can't be inverted; is a scale zero?")?;
+        let normal_matrix = inverse.fixed_view::<3, 3>(0, 0).transpose();
+        Ok(Transformed {
+            object,
+            matrix,
+            inverse,
+            normal_matrix,
+        })
+    }
+
+    pub fn matrix(&self) -> &Mat4 {
+        &self.matrix
+    }
+}
+
+impl<T: RayIntersect + 'static> RayIntersect for Transformed<T> {
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect {
+        let (origin, direction) = object_ray(&self.inverse, ray_origin, ray_direction);
+        let intersect = self.object.ray_intersect(&origin, &direction, time);
+        if !intersect.is_intersecting {
+            return intersect;
+        }
+        self.place_hit(intersect, ray_origin, ray_direction)
+    }
+
+    fn shadow_intersect(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+    ) -> Option<Intersect> {
+        let (origin, direction) = object_ray(&self.inverse, ray_origin, ray_direction);
+        let intersect = self.object.shadow_intersect(&origin, &direction, max_distance, time)?;
+        Some(self.place_hit(intersect, ray_origin, ray_direction))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl<T: RayIntersect> Transformed<T> {
+    fn place_hit(&self, intersect: Intersect, ray_origin: &Vec3, ray_direction: &Vec3) -> Intersect {
+        let linear: Mat3 = self.matrix.fixed_view::<3, 3>(0, 0).into();
+        world_hit(intersect, ray_origin, ray_direction, &linear, &self.normal_matrix)
+    }
+}
+
+/// The ray in the space `inverse` maps the world into. The direction is left
+/// unnormalized so hit distances stay in world units.
+pub fn object_ray(inverse: &Mat4, ray_origin: &Vec3, ray_direction: &Vec3) -> (Vec3, Vec3) {
+    let origin = (inverse * Vec4::new(ray_origin.x, ray_origin.y, ray_origin.z, 1.0)).xyz();
+    let direction = (inverse * Vec4::new(ray_direction.x, ray_direction.y, ray_direction.z, 0.0)).xyz();
+    (origin, direction)
+}
+
+/// A hit on a ray from `object_ray` moved back out into the world, with `linear`
+/// the placement's 3x3 part and `normal_matrix` its inverse transpose.
+pub fn world_hit(
+    mut intersect: Intersect,
+    ray_origin: &Vec3,
+    ray_direction: &Vec3,
+    linear: &Mat3,
+    normal_matrix: &Mat3,
+) -> Intersect {
+    intersect.point = ray_origin + ray_direction * intersect.distance;
+    intersect.normal = (normal_matrix * intersect.normal).normalize();
+    intersect.transform_surface(linear);
+    intersect
+}
+
+impl<T: RayIntersect + ?Sized> RayIntersect for Arc<T> {
+    fn ray_intersect(&self, ray_origin: &Vec3, ray_direction: &Vec3, time: f32) -> Intersect {
+        self.as_ref().ray_intersect(ray_origin, ray_direction, time)
+    }
+
+    fn shadow_intersect(
+        &self,
+        ray_origin: &Vec3,
+        ray_direction: &Vec3,
+        max_distance: f32,
+        time: f32,
+    ) -> Option<Intersect> {
+        self.as_ref().shadow_intersect(ray_origin, ray_direction, max_distance, time)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self.as_ref().as_any()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::cube::Cube;
+    use crate::material::{Material, UvTransform};
+
+    #[test]
+    fn world_space_uvs_tile_at_the_placed_size() {
+        let material = Material::new(Color::new(255, 255, 255), 10.0, [0.9, 0.1, 0.0, 0.0], 1.0, Color::black(), 0.0)
+            .with_uv_transform(UvTransform::world_space(1.0));
+        let cube = Cube::new(Vec3::zeros(), Vec3::new(1.0, 1.0, 1.0), material);
+        let placed = Transformed::new(cube, nalgebra_glm::scaling(&Vec3::new(2.0, 2.0, 2.0))).unwrap();
+
+        // The +Z face spans two world units, so tiling once per unit gives u = 1.5 at x = 1.5.
+        let hit = placed.ray_intersect(&Vec3::new(1.5, 1.0, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(hit.is_intersecting);
+        assert!((hit.u - 1.5).abs() < 1e-4, "u = {}", hit.u);
+        assert!((hit.dpdu.magnitude() - 1.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,459 @@
+use nalgebra_glm::Vec3;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::color::Color;
+use crate::cube::Cube;
+use crate::material::Material;
+use crate::structure::{self, Cell};
+
+/// One voxel of a model: position in the model's grid (Z up) and palette index, 1 to 255.
+#[derive(Debug, Clone, Copy)]
+pub struct Voxel {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+    pub color_index: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct VoxModel {
+    pub size: [u32; 3],
+    pub voxels: Vec<Voxel>,
+}
+
+/// Surface kinds of MagicaVoxel's renderer, from a `MATL` chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoxMaterialKind {
+    Diffuse,
+    Metal,
+    Glass,
+    Emit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VoxMaterial {
+    pub kind: VoxMaterialKind,
+    pub roughness: f32,
+    pub metal: f32,
+    /// How much light passes through glass, 0 to 1.
+    pub transparency: f32,
+    pub ior: f32,
+    pub emit: f32,
+    /// Extra emission power on top of `emit`, 0 to 4.
+    pub flux: f32,
+}
+
+impl Default for VoxMaterial {
+    fn default() -> Self {
+        VoxMaterial {
+            kind: VoxMaterialKind::Diffuse,
+            roughness: 0.5,
+            metal: 0.0,
+            transparency: 0.0,
+            ior: 1.3,
+            emit: 0.0,
+            flux: 0.0,
+        }
+    }
+}
+
+/// The contents of a MagicaVoxel `.vox` file. The scene graph chunks (`nTRN`,
+/// `nGRP`, `nSHP`) are skipped, so every model sits at the origin.
+#[derive(Debug, Clone)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA colors indexed by voxel color index; entry 0 is unused.
+    pub palette: [[u8; 4]; 256],
+    /// Materials by color index, for the entries a `MATL` chunk describes.
+    pub materials: HashMap<u8, VoxMaterial>,
+}
+
+impl VoxFile {
+    pub fn load(path: &str) -> Result<VoxFile, String> {
+        let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        VoxFile::parse(&data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<VoxFile, String> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.bytes(4)? != b"VOX " {
+            return Err("not a MagicaVoxel file".to_string());
+        }
+        let _version = reader.u32()?;
+
+        let mut file = VoxFile {
+            models: Vec::new(),
+            palette: default_palette(),
+            materials: HashMap::new(),
+        };
+        let mut size = None;
+
+        // MAIN only wraps the other chunks, so its header is read and its children walked flat.
+        if reader.bytes(4)? != b"MAIN" {
+            return Err("missing MAIN chunk".to_string());
+        }
+        let _ = (reader.u32()?, reader.u32()?);
+
+        while reader.pos < data.len() {
+            let id = reader.bytes(4)?;
+            let content_size = reader.u32()? as usize;
+            let children_size = reader.u32()? as usize;
+            let mut content = Reader {
+                data: reader.bytes(content_size)?,
+                pos: 0,
+            };
+            reader.bytes(children_size)?;
+
+            match id {
+                b"SIZE" => size = Some([content.u32()?, content.u32()?, content.u32()?]),
+                b"XYZI" => {
+                    let size = size.take().ok_or("XYZI chunk without a SIZE chunk before it")?;
+                    let count = content.u32()? as usize;
+                    let mut voxels = Vec::with_capacity(count.min(content.remaining() / 4));
+                    for _ in 0..count {
+                        let [x, y, z, color_index] = content.array()?;
+                        voxels.push(Voxel { x, y, z, color_index });
+                    }
+                    file.models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    // Entry i of the chunk is color index i + 1.
+                    for index in 1..256 {
+                        file.palette[index] = content.array()?;
+                    }
+                }
+                b"MATL" => {
+                    let index = content.u32()?;
+                    let properties = content.dict()?;
+                    if (1..256).contains(&index) {
+                        file.materials.insert(index as u8, VoxMaterial::from_properties(&properties));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// The material for a palette entry: its color, plus whatever `MATL` says about
+    /// shine, metal, glass and emission.
+    pub fn material(&self, color_index: u8) -> Material {
+        let [r, g, b, _] = self.palette[color_index as usize];
+        let color = Color::new(r, g, b);
+        let vox = self.materials.get(&color_index).copied().unwrap_or_default();
+
+        let smoothness = 1.0 - vox.roughness.clamp(0.0, 1.0);
+        let mut material = Material::new(
+            color,
+            1.0 + 127.0 * smoothness * smoothness,
+            [0.9, 0.3 * smoothness, 0.0, 0.0],
+            vox.ior,
+            Color::black(),
+            0.0,
+        );
+        match vox.kind {
+            VoxMaterialKind::Diffuse => {}
+            VoxMaterialKind::Metal => {
+                material.albedo[2] = vox.metal.clamp(0.0, 1.0) * 0.8;
+            }
+            VoxMaterialKind::Glass => {
+                let transparency = vox.transparency.clamp(0.0, 1.0);
+                material.albedo[0] = 0.9 * (1.0 - transparency);
+                material.albedo[3] = transparency;
+            }
+            VoxMaterialKind::Emit => {
+                material.emission_color = color;
+                // Full emission without extra flux glows like glowstone.
+                material.emission_intensity = 0.3 * vox.emit * (1.0 + vox.flux);
+            }
+        }
+        material
+    }
+
+    /// Blocks of `block_size` for every voxel of every model, turned Y-up and with
+    /// each model's footprint centered over `origin`. Voxels buried behind opaque
+    /// neighbours on all six sides can't be seen and are left out.
+    pub fn blocks(&self, origin: Vec3, block_size: f32) -> Vec<Cube> {
+        let mut materials: HashMap<u8, Material> = HashMap::new();
+        let mut blocks = Vec::new();
+
+        for model in &self.models {
+            let (half_x, half_y) = (model.size[0] as i32 / 2, model.size[1] as i32 / 2);
+            // MagicaVoxel is Z-up; its +Y runs away from the viewer, which is -Z here.
+            let cell = |voxel: &Voxel| -> Cell {
+                [voxel.x as i32 - half_x, voxel.z as i32, half_y - voxel.y as i32]
+            };
+
+            let opaque: HashSet<Cell> = model
+                .voxels
+                .iter()
+                .filter(|voxel| self.is_opaque(voxel.color_index))
+                .map(cell)
+                .collect();
+
+            for voxel in &model.voxels {
+                let cell = cell(voxel);
+                if structure::is_enclosed(cell, &opaque) {
+                    continue;
+                }
+
+                let material = materials
+                    .entry(voxel.color_index)
+                    .or_insert_with(|| self.material(voxel.color_index));
+                let (min, max) = structure::cell_bounds(cell, origin, block_size);
+                blocks.push(Cube::new(min, max, material.clone()));
+            }
+        }
+        blocks
+    }
+
+    fn is_opaque(&self, color_index: u8) -> bool {
+        self.materials
+            .get(&color_index)
+            .is_none_or(|material| material.kind != VoxMaterialKind::Glass)
+    }
+}
+
+impl VoxMaterial {
+    fn from_properties(properties: &HashMap<String, String>) -> Self {
+        let mut material = VoxMaterial::default();
+        let number = |key: &str| properties.get(key).and_then(|value| value.parse::<f32>().ok());
+
+        material.kind = match properties.get("_type").map(String::as_str) {
+            Some("_metal") => VoxMaterialKind::Metal,
+            Some("_glass") => VoxMaterialKind::Glass,
+            Some("_emit") => VoxMaterialKind::Emit,
+            _ => VoxMaterialKind::Diffuse,
+        };
+        if let Some(roughness) = number("_rough") {
+            material.roughness = roughness;
+        }
+        if let Some(metal) = number("_metal") {
+            material.metal = metal;
+        }
+        if let Some(transparency) = number("_trans").or_else(|| number("_alpha")) {
+            material.transparency = transparency;
+        }
+        // Newer files store the index of refraction minus one as `_ior`; older ones the full value as `_ri`.
+        if let Some(ior) = number("_ior").map(|ior| ior + 1.0).or_else(|| number("_ri")) {
+            material.ior = ior;
+        }
+        if let Some(emit) = number("_emit") {
+            material.emit = emit;
+        }
+        if let Some(flux) = number("_flux") {
+            material.flux = flux;
+        }
+        material
+    }
+}
+
+/// Little-endian cursor over chunk data.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(count).filter(|&end| end <= self.data.len());
+        let end = end.ok_or("file ends in the middle of a chunk")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, String> {
+        let count = self.u32()?;
+        let mut dict = HashMap::new();
+        for _ in 0..count {
+            let key = self.string()?;
+            let value = self.string()?;
+            dict.insert(key, value);
+        }
+        Ok(dict)
+    }
+}
+
+/// The palette MagicaVoxel assumes when a file has no `RGBA` chunk: a 6x6x6 color
+/// cube from white down to black, then ramps of blue, green, red and gray.
+fn default_palette() -> [[u8; 4]; 256] {
+    let mut palette = [[0u8; 4]; 256];
+    let steps = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut index = 1;
+    for r in steps {
+        for g in steps {
+            for b in steps {
+                if index < 216 {
+                    palette[index] = [r, g, b, 0xff];
+                    index += 1;
+                }
+            }
+        }
+    }
+    for channel in [2, 1, 0] {
+        for value in ramp {
+            let mut color = [0, 0, 0, 0xff];
+            color[channel] = value;
+            palette[index] = color;
+            index += 1;
+        }
+    }
+    for value in ramp {
+        palette[index] = [value, value, value, 0xff];
+        index += 1;
+    }
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((content.len() as u32).to_le_bytes());
+        chunk.extend(0u32.to_le_bytes());
+        chunk.extend(content);
+        chunk
+    }
+
+    fn file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let children: Vec<u8> = chunks.concat();
+        let mut data = b"VOX ".to_vec();
+        data.extend(150u32.to_le_bytes());
+        data.extend(b"MAIN");
+        data.extend(0u32.to_le_bytes());
+        data.extend((children.len() as u32).to_le_bytes());
+        data.extend(children);
+        data
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn matl(index: u32, properties: &[(&str, &str)]) -> Vec<u8> {
+        let mut content = words(&[index, properties.len() as u32]);
+        for text in properties.iter().flat_map(|(key, value)| [key, value]) {
+            content.extend((text.len() as u32).to_le_bytes());
+            content.extend(text.as_bytes());
+        }
+        chunk(b"MATL", &content)
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut voxels = words(&[2]);
+        voxels.extend([0, 1, 2, 1, 1, 2, 3, 2]);
+        let mut palette = vec![0u8; 256 * 4];
+        palette[..4].copy_from_slice(&[10, 20, 30, 255]);
+        palette[4..8].copy_from_slice(&[200, 210, 220, 128]);
+        file(&[
+            chunk(b"SIZE", &words(&[2, 3, 4])),
+            chunk(b"XYZI", &voxels),
+            chunk(b"RGBA", &palette),
+            matl(1, &[("_type", "_emit"), ("_emit", "1"), ("_flux", "1")]),
+            matl(2, &[("_type", "_glass"), ("_trans", "0.5"), ("_ior", "0.5")]),
+        ])
+    }
+
+    #[test]
+    fn reads_models_palette_and_materials() {
+        let vox = VoxFile::parse(&sample()).unwrap();
+
+        assert_eq!(vox.models.len(), 1);
+        assert_eq!(vox.models[0].size, [2, 3, 4]);
+        let voxels: Vec<_> = vox.models[0]
+            .voxels
+            .iter()
+            .map(|voxel| [voxel.x, voxel.y, voxel.z, voxel.color_index])
+            .collect();
+        assert_eq!(voxels, [[0, 1, 2, 1], [1, 2, 3, 2]]);
+
+        // RGBA entry 0 is color index 1.
+        assert_eq!(vox.palette[1], [10, 20, 30, 255]);
+        assert_eq!(vox.palette[2], [200, 210, 220, 128]);
+
+        let emit = vox.materials[&1];
+        assert_eq!(emit.kind, VoxMaterialKind::Emit);
+        assert!((vox.material(1).emission_intensity - 0.6).abs() < 1e-6);
+
+        let glass = vox.materials[&2];
+        assert_eq!(glass.kind, VoxMaterialKind::Glass);
+        assert!((glass.ior - 1.5).abs() < 1e-6);
+        assert!((vox.material(2).albedo[3] - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn uses_the_default_palette_without_an_rgba_chunk() {
+        let mut voxels = words(&[1]);
+        voxels.extend([0, 0, 0, 1]);
+        let vox = VoxFile::parse(&file(&[chunk(b"SIZE", &words(&[1, 1, 1])), chunk(b"XYZI", &voxels)])).unwrap();
+        assert_eq!(vox.palette[1], [255, 255, 255, 255]);
+        assert!(vox.materials.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_and_malformed_files() {
+        let data = sample();
+        for length in [3, 10, 25, data.len() - 1] {
+            assert!(VoxFile::parse(&data[..length]).is_err(), "{} bytes", length);
+        }
+
+        let mut wrong_magic = data.clone();
+        wrong_magic[..4].copy_from_slice(b"VOXX");
+        assert!(VoxFile::parse(&wrong_magic).is_err());
+
+        let mut voxels = words(&[1]);
+        voxels.extend([0, 0, 0, 1]);
+        assert!(VoxFile::parse(&file(&[chunk(b"XYZI", &voxels)])).is_err());
+
+        // A count claiming more voxels than the chunk holds.
+        let mut short = words(&[3]);
+        short.extend([0, 0, 0, 1]);
+        assert!(VoxFile::parse(&file(&[chunk(b"SIZE", &words(&[1, 1, 1])), chunk(b"XYZI", &short)])).is_err());
+    }
+
+    #[test]
+    fn rejects_a_huge_voxel_count_without_reserving_for_it() {
+        let mut voxels = words(&[u32::MAX]);
+        voxels.extend([0, 0, 0, 1]);
+        let error = VoxFile::parse(&file(&[chunk(b"SIZE", &words(&[1, 1, 1])), chunk(b"XYZI", &voxels)]));
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn leaves_out_voxels_hidden_inside_the_model() {
+        let mut voxels = words(&[27]);
+        for z in 0..3 {
+            for y in 0..3 {
+                for x in 0..3 {
+                    voxels.extend([x, y, z, 1]);
+                }
+            }
+        }
+        let vox = VoxFile::parse(&file(&[chunk(b"SIZE", &words(&[3, 3, 3])), chunk(b"XYZI", &voxels)])).unwrap();
+        assert_eq!(vox.blocks(Vec3::zeros(), 1.0).len(), 26);
+    }
+}